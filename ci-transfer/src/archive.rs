@@ -0,0 +1,114 @@
+use clap::ArgEnum;
+use serde::Deserialize;
+use std::fs::read_dir;
+use std::io::Write;
+use std::path::Path;
+
+use crate::filter::FileFilter;
+
+/// Compression used by --archive
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Remote shell pipeline extracting the archive from stdin into `quoted_dir`. The headers
+    /// carry the runner's uid/gid, which tar would restore when extracting as root.
+    pub fn extract_command(&self, quoted_dir: &str) -> String {
+        match self {
+            Compression::Gzip => format!("mkdir -p {0} && tar --no-same-owner -xzf - -C {0}", quoted_dir),
+            Compression::Zstd => format!(
+                "mkdir -p {0} && zstd -dc | tar --no-same-owner -xf - -C {0}",
+                quoted_dir
+            ),
+        }
+    }
+}
+
+/// Append the contents of `dir` below `root` to the archive, keeping symlinks as links.
+/// Returns the number of files and symlinks written.
+fn append_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    dir: &Path,
+    filter: &FileFilter,
+) -> std::io::Result<usize> {
+    let mut count = 0;
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.strip_prefix(root).unwrap();
+        let file_type = path.symlink_metadata()?.file_type();
+        if !filter.allows(root, &path, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            builder.append_dir(name, &path)?;
+            count += append_tree(builder, root, &path, filter)?;
+        } else {
+            builder.append_path_with_name(&path, name)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Stream a compressed tarball of `local_dir` into `writer`, returning the number of files
+pub fn write_archive<W: Write>(
+    writer: W,
+    local_dir: &Path,
+    compression: Compression,
+    filter: &FileFilter,
+) -> std::io::Result<usize> {
+    match compression {
+        Compression::Gzip => {
+            let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            let mut builder = tar::Builder::new(encoder);
+            builder.follow_symlinks(false);
+            let count = append_tree(&mut builder, local_dir, local_dir, filter)?;
+            builder.into_inner()?.finish()?;
+            Ok(count)
+        }
+        Compression::Zstd => {
+            let encoder = zstd::Encoder::new(writer, 3)?;
+            let mut builder = tar::Builder::new(encoder);
+            builder.follow_symlinks(false);
+            let count = append_tree(&mut builder, local_dir, local_dir, filter)?;
+            builder.into_inner()?.finish()?;
+            Ok(count)
+        }
+    }
+}
+
+#[test]
+fn test_write_archive() {
+    let mut buffer = Vec::new();
+    let filter = FileFilter::new(&[], &["filter.rs".to_string()], None).unwrap();
+    let count = write_archive(&mut buffer, Path::new("src"), Compression::Gzip, &filter).unwrap();
+    assert!(count > 0);
+    assert_eq!(filter.excluded(), 1);
+
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(buffer.as_slice()));
+    let names: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert!(names.contains(&"main.rs".to_string()));
+    assert!(names.contains(&"archive.rs".to_string()));
+    assert!(!names.contains(&"filter.rs".to_string()));
+}
+
+#[test]
+fn test_extract_command() {
+    assert_eq!(
+        Compression::Gzip.extract_command("'/srv/app'"),
+        "mkdir -p '/srv/app' && tar --no-same-owner -xzf - -C '/srv/app'"
+    );
+    assert_eq!(
+        Compression::Zstd.extract_command("'/srv/app'"),
+        "mkdir -p '/srv/app' && zstd -dc | tar --no-same-owner -xf - -C '/srv/app'"
+    );
+}
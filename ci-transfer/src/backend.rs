@@ -0,0 +1,433 @@
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::command::{decode_command, shell_quote, split_command_options, CommandResult};
+use crate::error::TransferError;
+use crate::filter::FileFilter;
+use crate::template::TemplateVars;
+use crate::Args;
+
+/// Counters reported at the end of a transfer
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub uploaded: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    /// Bytes of the uploaded files, skipped files not included
+    pub bytes: u64,
+    /// Commands run on the destination with their stage, e.g. "pre-transfer"
+    pub commands: Vec<(&'static str, CommandResult)>,
+}
+
+impl TransferStats {
+    /// Record the result of a command started at `started`, a failed one too before returning its error
+    fn add_command(
+        &mut self,
+        stage: &'static str,
+        result: Result<CommandResult, TransferError>,
+        started: Instant,
+    ) -> Result<(), TransferError> {
+        match result {
+            Ok(result) => self.commands.push((stage, result)),
+            Err(e) => {
+                if let Some(result) = CommandResult::from_error(&e, started.elapsed()) {
+                    self.commands.push((stage, result));
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A local file and the remote path (or object key) it is transferred to
+pub struct FileJob {
+    pub local_path: PathBuf,
+    pub remote: String,
+    pub size: u64,
+}
+
+/// One `--source` mapped onto the destination
+pub struct SourcePlan {
+    pub local: PathBuf,
+    pub remote: String,
+    /// Directories of a directory source as (local, remote) pairs, the source itself first and
+    /// every parent before its children; empty for a file source
+    pub dirs: Vec<(PathBuf, String)>,
+    pub files: Vec<FileJob>,
+}
+
+impl SourcePlan {
+    pub fn is_dir(&self) -> bool {
+        !self.dirs.is_empty()
+    }
+}
+
+/// Where the files of a directory source go
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirLayout {
+    /// Into a directory named like the source when the root ends with `/` or there are several
+    /// sources, the same rule as for files (SSH and local destinations)
+    Named,
+    /// Straight under the root, keeping the structure below the source (object storage keys)
+    Contents,
+}
+
+/// A destination files are transferred to: SSH host, object storage bucket or local directory
+pub trait Backend: Sync {
+    /// Label used in progress output
+    fn name(&self) -> &'static str;
+
+    /// Number of files uploaded at the same time
+    fn concurrency(&self) -> usize {
+        1
+    }
+
+    /// How directory sources are mapped onto the destination
+    fn dir_layout(&self) -> DirLayout {
+        DirLayout::Named
+    }
+
+    /// Create a remote directory whose parent already exists
+    fn make_dir(&self, remote_dir: &str) -> Result<(), TransferError>;
+
+    /// Transfer one file, adding the bytes sent to `progress`.
+    /// Returns the reason when the file was skipped instead.
+    fn put_file(&self, job: &FileJob, progress: &Progress) -> Result<Option<&'static str>, TransferError>;
+
+    /// Run a shell command on the destination
+    fn run_command(&self, command: &str) -> Result<CommandResult, TransferError>;
+
+    /// Run a local script file on the destination with `arguments`; `options` are the
+    /// `allow-failure:` / `timeout=<secs>:` prefixes of the command running it
+    fn run_script(&self, script: &Path, options: &str, arguments: &str) -> Result<CommandResult, TransferError> {
+        let script = shell_quote(&script.to_string_lossy());
+        self.run_command(&format!("{}{} {}", options, script, arguments))
+    }
+
+    /// Transfer a whole directory source at once (e.g. as one archive).
+    /// Returns false to have its directories and files transferred one by one.
+    fn put_tree(&self, _source: &SourcePlan, _filter: &FileFilter) -> Result<bool, TransferError> {
+        Ok(false)
+    }
+
+    /// Called after a directory source was transferred, e.g. to remove remote files that no
+    /// longer exist locally. Returns the number of files deleted.
+    fn finish_tree(&self, _source: &SourcePlan, _filter: &FileFilter) -> Result<usize, TransferError> {
+        Ok(0)
+    }
+}
+
+/// Aggregate progress over all files of one transfer
+pub struct Progress {
+    label: &'static str,
+    files: usize,
+    total: u64,
+    done_files: AtomicUsize,
+    skipped_files: AtomicUsize,
+    done_bytes: AtomicU64,
+    skipped_bytes: AtomicU64,
+    started: Instant,
+}
+
+impl Progress {
+    fn new(label: &'static str, jobs: &[&FileJob]) -> Self {
+        Progress {
+            label,
+            files: jobs.len(),
+            total: jobs.iter().map(|job| job.size).sum(),
+            done_files: AtomicUsize::new(0),
+            skipped_files: AtomicUsize::new(0),
+            done_bytes: AtomicU64::new(0),
+            skipped_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.done_bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn finish_file(&self, label: &str) {
+        self.done_files.fetch_add(1, Ordering::SeqCst);
+        self.report(label);
+    }
+
+    fn skip_file(&self, job: &FileJob, reason: &str) {
+        self.skipped_files.fetch_add(1, Ordering::SeqCst);
+        self.skipped_bytes.fetch_add(job.size, Ordering::SeqCst);
+        self.add_bytes(job.size);
+        self.finish_file(&format!("{} skipped ({})", job.remote, reason));
+    }
+
+    pub fn report(&self, label: &str) {
+        let done = self.done_bytes.load(Ordering::SeqCst);
+        let percentage = if self.total == 0 {
+            100.0
+        } else {
+            done as f64 / self.total as f64 * 100.0
+        };
+        let speed = done as f64 / self.started.elapsed().as_secs_f64() / 1024.0 / 1024.0;
+        println!(
+            "{} transfer: [{}/{}] {} - {:.2}% ({}/{} bytes) - {:.2} MB/s",
+            self.label,
+            self.done_files.load(Ordering::SeqCst),
+            self.files,
+            label,
+            percentage,
+            done,
+            self.total,
+            speed
+        );
+    }
+}
+
+/// Target of one source: below `remote_root` when it ends with `/` or there are several
+/// sources, otherwise `remote_root` itself. With [`DirLayout::Contents`] a directory source
+/// always maps onto `remote_root`.
+fn source_target(remote_root: &str, source_path: &Path, sources: usize, layout: DirLayout) -> String {
+    let name = source_path.file_name().unwrap().to_str().unwrap();
+    if layout == DirLayout::Contents && source_path.is_dir() {
+        remote_root.to_string()
+    } else if remote_root.ends_with('/') {
+        format!("{}{}", remote_root, name)
+    } else if sources > 1 {
+        format!("{}/{}", remote_root.trim_end_matches('/'), name)
+    } else {
+        remote_root.to_string()
+    }
+}
+
+/// Map every source onto `remote_root`, walking directories through the filter
+pub fn plan(
+    args: &Args,
+    remote_root: &str,
+    filter: &FileFilter,
+    layout: DirLayout,
+) -> Result<Vec<SourcePlan>, TransferError> {
+    let sources = &args.source;
+    let mut plans = Vec::new();
+    for source in sources {
+        let source_path = Path::new(source);
+
+        if !source_path.exists() {
+            return Err(TransferError::Other(format!(
+                "Source path {} does not exist",
+                source
+            )));
+        }
+
+        let remote = source_target(remote_root, source_path, sources.len(), layout);
+        let mut plan = SourcePlan {
+            local: source_path.to_path_buf(),
+            remote: remote.clone(),
+            dirs: Vec::new(),
+            files: Vec::new(),
+        };
+        if source_path.is_dir() {
+            plan.dirs.push((source_path.to_path_buf(), remote));
+            let mut index = 0;
+            while index < plan.dirs.len() {
+                let (local_dir, remote_dir) = plan.dirs[index].clone();
+                index += 1;
+                for entry in read_dir(&local_dir)? {
+                    let path = entry?.path();
+                    if !filter.allows(source_path, &path, path.is_dir()) {
+                        continue;
+                    }
+                    let file_name = path.file_name().unwrap().to_str().unwrap();
+                    let remote_path = format!("{}/{}", remote_dir.trim_end_matches('/'), file_name);
+                    if path.is_dir() {
+                        plan.dirs.push((path, remote_path));
+                    } else if path.is_file() {
+                        let size = path.metadata()?.len();
+                        plan.files.push(FileJob {
+                            local_path: path,
+                            remote: remote_path,
+                            size,
+                        });
+                    }
+                }
+            }
+        } else if source_path.is_file() {
+            plan.files.push(FileJob {
+                local_path: source_path.to_path_buf(),
+                size: source_path.metadata()?.len(),
+                remote,
+            });
+        } else {
+            return Err(TransferError::Other(format!(
+                "Path {} is neither a file nor directory",
+                source
+            )));
+        }
+        plans.push(plan);
+    }
+    Ok(plans)
+}
+
+/// Put `jobs` through the backend on up to `concurrency()` threads; workers stop taking new
+/// files once any of them has failed
+fn put_files(backend: &dyn Backend, jobs: &[FileJob], progress: &Progress) -> Result<(), TransferError> {
+    let next = AtomicUsize::new(0);
+    let error: Mutex<Option<TransferError>> = Mutex::new(None);
+
+    std::thread::scope(|scope| {
+        for _ in 0..backend.concurrency().clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= jobs.len() || error.lock().unwrap().is_some() {
+                    break;
+                }
+                let job = &jobs[index];
+                match backend.put_file(job, progress) {
+                    Ok(Some(reason)) => progress.skip_file(job, reason),
+                    Ok(None) => progress.finish_file(&job.remote),
+                    Err(e) => {
+                        println!("{} transfer: {} failed", backend.name(), job.remote);
+                        error.lock().unwrap().get_or_insert(e);
+                    }
+                }
+            });
+        }
+    });
+
+    match error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Transfer all `--source` paths to `remote_root` through `backend`, adding to `stats` as it
+/// goes so a failed transfer still counts the files that made it
+pub fn transfer(
+    backend: &dyn Backend,
+    args: &Args,
+    remote_root: &str,
+    stats: &mut TransferStats,
+) -> Result<(), TransferError> {
+    // Handle empty sources case (side-effect only)
+    if args.source.is_empty() {
+        println!(
+            "No source files specified for {} transfer - side-effect only operation",
+            backend.name().to_uppercase()
+        );
+        return Ok(());
+    }
+
+    let filter = FileFilter::from_args(args)?;
+    let plans = plan(args, remote_root, &filter, backend.dir_layout())?;
+    if filter.excluded() > 0 {
+        println!(
+            "{} transfer: excluded {} file(s) by filters",
+            backend.name(),
+            filter.excluded()
+        );
+    }
+    let all_files: Vec<&FileJob> = plans.iter().flat_map(|plan| &plan.files).collect();
+    let progress = Progress::new(backend.name(), &all_files);
+
+    let result = transfer_plans(backend, &plans, &filter, &progress, stats);
+    let skipped = progress.skipped_files.load(Ordering::SeqCst);
+    stats.uploaded += progress.done_files.load(Ordering::SeqCst) - skipped;
+    stats.skipped += skipped;
+    stats.bytes += progress.done_bytes.load(Ordering::SeqCst) - progress.skipped_bytes.load(Ordering::SeqCst);
+    println!(
+        "{} transfer: {} uploaded, {} skipped, {} deleted",
+        backend.name(),
+        stats.uploaded,
+        stats.skipped,
+        stats.deleted
+    );
+    result
+}
+
+fn transfer_plans(
+    backend: &dyn Backend,
+    plans: &[SourcePlan],
+    filter: &FileFilter,
+    progress: &Progress,
+    stats: &mut TransferStats,
+) -> Result<(), TransferError> {
+    for plan in plans {
+        println!("Transferring: {} -> {}", plan.local.display(), plan.remote);
+        if plan.is_dir() && backend.put_tree(plan, filter)? {
+            stats.uploaded += plan.files.len();
+            stats.bytes += plan.files.iter().map(|job| job.size).sum::<u64>();
+            continue;
+        }
+        for (_, remote_dir) in &plan.dirs {
+            backend.make_dir(remote_dir)?;
+        }
+        put_files(backend, &plan.files, progress)?;
+        if plan.is_dir() {
+            stats.deleted += backend.finish_tree(plan, filter)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `commands` through the backend between "Executing <stage> commands" banners,
+/// after decoding them and filling in their `{{...}}` variables. Every command run is added to
+/// `stats`, including the one that failed.
+pub fn run_commands(
+    backend: &dyn Backend,
+    stage: &'static str,
+    commands: &[String],
+    vars: &TemplateVars,
+    stats: &mut TransferStats,
+) -> Result<(), TransferError> {
+    if commands.is_empty() {
+        return Ok(());
+    }
+    println!("Executing {} commands:", stage);
+    for command in commands.iter().filter(|command| !command.is_empty()) {
+        let command = vars.render(&decode_command(command))?;
+        let started = Instant::now();
+        let result = backend.run_command(&command);
+        stats.add_command(stage, result, started)?;
+    }
+    let mut title = stage.to_string();
+    title[..1].make_ascii_uppercase();
+    println!("{} commands completed.", title);
+    Ok(())
+}
+
+/// Run every `--prescript` / `--script`, given as "path [arguments]" with the same prefixes and
+/// `{{...}}` variables as commands
+pub fn run_scripts(
+    backend: &dyn Backend,
+    stage: &'static str,
+    scripts: &[String],
+    vars: &TemplateVars,
+    stats: &mut TransferStats,
+) -> Result<(), TransferError> {
+    if scripts.is_empty() {
+        return Ok(());
+    }
+    println!("Executing {} scripts:", stage);
+    for script in scripts.iter().filter(|script| !script.is_empty()) {
+        let script = vars.render(script)?;
+        let (options, rest) = split_command_options(&script);
+        let (path, arguments) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if !Path::new(path).is_file() {
+            return Err(TransferError::Other(format!("Script {} does not exist", path)));
+        }
+        let started = Instant::now();
+        let result = backend.run_script(Path::new(path), options, arguments.trim());
+        let recorded = stats.commands.len();
+        let outcome = stats.add_command(stage, result, started);
+        // Reported as given, not as the temporary copy the backend ran
+        if let Some((_, result)) = stats.commands.get_mut(recorded) {
+            result.command = rest.to_string();
+        }
+        outcome?;
+    }
+    let mut title = stage.to_string();
+    title[..1].make_ascii_uppercase();
+    println!("{} scripts completed.", title);
+    Ok(())
+}
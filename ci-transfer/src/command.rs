@@ -0,0 +1,522 @@
+use base64::{engine::general_purpose, Engine as _};
+use clap::ArgEnum;
+use serde::Deserialize;
+use ssh2::{Channel, Session};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::error::TransferError;
+use crate::template::env_prefix;
+
+/// Prefix marking a command whose non-zero exit status should not fail the run
+const ALLOW_FAILURE_PREFIX: &str = "allow-failure:";
+
+/// Prefix overriding the command timeout, e.g. `timeout=600: ./migrate.sh`
+const TIMEOUT_PREFIX: &str = "timeout=";
+
+/// A command with its leading `allow-failure:` / `timeout=<secs>:` options stripped
+#[derive(Debug, PartialEq)]
+struct CommandSpec<'a> {
+    command: &'a str,
+    allow_failure: bool,
+    timeout: Option<Duration>,
+}
+
+fn parse_command_spec(command: &str) -> CommandSpec<'_> {
+    let mut spec = CommandSpec {
+        command: command.trim_start(),
+        allow_failure: false,
+        timeout: None,
+    };
+    loop {
+        if let Some(rest) = spec.command.strip_prefix(ALLOW_FAILURE_PREFIX) {
+            spec.allow_failure = true;
+            spec.command = rest.trim_start();
+            continue;
+        }
+        if let Some(rest) = spec.command.strip_prefix(TIMEOUT_PREFIX) {
+            if let Some((seconds, rest)) = rest.split_once(':') {
+                if let Ok(seconds) = seconds.trim().parse::<u64>() {
+                    spec.timeout = Some(Duration::from_secs(seconds));
+                    spec.command = rest.trim_start();
+                    continue;
+                }
+            }
+        }
+        return spec;
+    }
+}
+
+/// Split `command` into its leading `allow-failure:` / `timeout=<secs>:` options and the rest
+pub fn split_command_options(command: &str) -> (&str, &str) {
+    let rest = parse_command_spec(command).command;
+    (&command[..command.len() - rest.len()], rest)
+}
+
+/// Prints remote output line by line as it arrives, keeping incomplete lines until the next chunk
+struct LinePrinter {
+    prefix: String,
+    pending: Vec<u8>,
+}
+
+impl LinePrinter {
+    fn new(prefix: String) -> Self {
+        LinePrinter {
+            prefix,
+            pending: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        while let Some(position) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=position).collect();
+            let line = String::from_utf8_lossy(&line);
+            println!("{} {}", self.prefix, line.trim_end_matches(['\r', '\n']));
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            println!("{} {}", self.prefix, String::from_utf8_lossy(&self.pending));
+            self.pending.clear();
+        }
+    }
+}
+
+/// Print everything read from `reader` line by line on a background thread
+fn print_lines(mut reader: impl Read + Send + 'static, prefix: &str) -> JoinHandle<()> {
+    let mut printer = LinePrinter::new(prefix.to_string());
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 8192];
+        while let Ok(n) = reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            printer.push(&buffer[..n]);
+        }
+        printer.flush();
+    })
+}
+
+/// Decode a base64 encoded command (repeatedly), other commands are returned unchanged
+pub fn decode_command(command: &str) -> Cow<'_, str> {
+    if let Ok(decoded) = general_purpose::STANDARD.decode(command) {
+        if let Ok(decoded_str) = String::from_utf8(decoded) {
+            return Cow::Owned(decode_command(&decoded_str).into_owned());
+        }
+    }
+    Cow::Borrowed(command)
+}
+
+/// Exit status of one executed command
+#[derive(Debug)]
+pub struct CommandResult {
+    /// Decoded command without its `allow-failure:` / `timeout=<secs>:` options
+    pub command: String,
+    pub status: i32,
+    pub duration: Duration,
+}
+
+impl CommandResult {
+    /// The result of a command that stopped the run, with -1 as status when it timed out
+    pub fn from_error(error: &TransferError, duration: Duration) -> Option<Self> {
+        let (command, status) = match error {
+            TransferError::CommandFailed { command, status } => (command, *status),
+            TransferError::CommandTimedOut { command, .. } => (command, -1),
+            _ => return None,
+        };
+        Some(CommandResult {
+            command: command.clone(),
+            status,
+            duration,
+        })
+    }
+}
+
+/// Run a command, already decoded by the caller, with `run`, failing on a non-zero exit status
+/// unless the command is marked `allow-failure:`
+fn execute_command(
+    command: &str,
+    run: &dyn Fn(&CommandSpec) -> Result<i32, TransferError>,
+) -> Result<CommandResult, TransferError> {
+    let spec = parse_command_spec(command);
+    println!("Command: {}", spec.command);
+    let started = Instant::now();
+    let status = run(&spec)?;
+    println!("Exit status: {}", status);
+    println!("---");
+    if status != 0 {
+        if !spec.allow_failure {
+            return Err(TransferError::CommandFailed {
+                command: spec.command.to_string(),
+                status,
+            });
+        }
+        println!("Ignoring failure of allow-failure command");
+    }
+    Ok(CommandResult {
+        command: spec.command.to_string(),
+        status,
+        duration: started.elapsed(),
+    })
+}
+
+/// The earlier of the command's own timeout and the deadline of the whole run
+fn command_deadline(
+    started: Instant,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) -> Option<Instant> {
+    match (timeout.map(|timeout| started + timeout), deadline) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Shell that runs every command, --shell
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Bash,
+    Sh,
+    /// The command line is given to the remote login shell as it is
+    None,
+}
+
+impl Shell {
+    /// Command line for the remote login shell running `command` in this shell with `env` exported
+    pub fn wrap(&self, command: &str, env: &[(String, String)]) -> String {
+        match self {
+            Shell::Bash => format!("{}bash -c {}", env_prefix(env), shell_quote(command)),
+            Shell::Sh => format!("{}sh -c {}", env_prefix(env), shell_quote(command)),
+            Shell::None if env.is_empty() => command.to_string(),
+            Shell::None => format!("export {}; {}", env_prefix(env).trim_end(), command),
+        }
+    }
+
+    /// Program running commands of local destinations, which have no login shell to hand them to,
+    /// and uploaded scripts without a `#!` line
+    pub fn local_program(&self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Sh | Shell::None => "sh",
+        }
+    }
+}
+
+/// Program line an uploaded script is run with: its `#!` line, or the --shell program.
+/// Scripts are passed to it instead of being executed, as /tmp is often mounted noexec.
+pub fn script_interpreter(script: &Path, shell: Shell) -> Result<String, TransferError> {
+    let mut first_line = String::new();
+    // A script that is not valid UTF-8 has no usable shebang either
+    let _ = BufReader::new(File::open(script)?).read_line(&mut first_line);
+    match first_line.strip_prefix("#!").map(str::split_whitespace) {
+        Some(words) if first_line.len() > 2 => Ok(words.map(shell_quote).collect::<Vec<_>>().join(" ")),
+        _ => Ok(shell.local_program().to_string()),
+    }
+}
+
+/// Quote `value` as one word for a POSIX shell: wrapped in single quotes, inside which only `'`
+/// itself needs escaping (as `'\''`)
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Runs commands on this machine for local destinations, with the same prefixes and timeouts
+pub struct LocalRunner {
+    command_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    env: Vec<(String, String)>,
+    shell: Shell,
+}
+
+impl LocalRunner {
+    /// `env` is exported to every command, see `template::command_env`
+    pub fn new(
+        command_timeout: Option<u64>,
+        deadline: Option<Instant>,
+        env: Vec<(String, String)>,
+        shell: Shell,
+    ) -> Self {
+        LocalRunner {
+            command_timeout: command_timeout.map(Duration::from_secs),
+            deadline,
+            env,
+            shell,
+        }
+    }
+
+    pub fn execute(&self, command: &str) -> Result<CommandResult, TransferError> {
+        execute_command(command, &|spec| self.run(spec))
+    }
+
+    fn run(&self, spec: &CommandSpec) -> Result<i32, TransferError> {
+        let started = Instant::now();
+        let deadline = command_deadline(started, spec.timeout.or(self.command_timeout), self.deadline);
+        // Output is piped through LinePrinter so it is masked like everything else we print
+        let mut child = std::process::Command::new(self.shell.local_program())
+            .arg("-c")
+            .arg(spec.command)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = print_lines(child.stdout.take().unwrap(), "[local]");
+        let stderr = print_lines(child.stderr.take().unwrap(), "[local stderr]");
+        loop {
+            if let Some(status) = child.try_wait()? {
+                let _ = stdout.join();
+                let _ = stderr.join();
+                return Ok(status.code().unwrap_or(-1));
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(TransferError::CommandTimedOut {
+                    command: spec.command.to_string(),
+                    seconds: started.elapsed().as_secs(),
+                });
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// Run `command` under the remote `timeout`, which kills its whole process group: closing the
+/// channel at the deadline does not stop the command. It fires a second after our own deadline,
+/// so the run still fails with CommandTimedOut rather than timeout's exit status 124.
+fn remote_timeout(command: &str, remaining: Duration) -> String {
+    let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0) + 1;
+    format!("timeout -k 5 {} sh -c {}", seconds, shell_quote(command))
+}
+
+/// Runs commands on one SSH session, streaming their output and enforcing timeouts
+pub struct CommandRunner<'a> {
+    session: &'a Session,
+    host: &'a str,
+    command_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    env: &'a [(String, String)],
+    shell: Shell,
+}
+
+impl<'a> CommandRunner<'a> {
+    /// `command_timeout` applies to every command, `deadline` to all commands of this run,
+    /// `env` is exported to every command run by `shell`
+    pub fn new(
+        session: &'a Session,
+        host: &'a str,
+        command_timeout: Option<u64>,
+        deadline: Option<Instant>,
+        env: &'a [(String, String)],
+        shell: Shell,
+    ) -> Self {
+        CommandRunner {
+            session,
+            host,
+            command_timeout: command_timeout.map(Duration::from_secs),
+            deadline,
+            env,
+            shell,
+        }
+    }
+
+    pub fn execute(&self, command: &str) -> Result<CommandResult, TransferError> {
+        execute_command(command, &|spec| self.run(spec))
+    }
+
+    fn run(&self, spec: &CommandSpec) -> Result<i32, TransferError> {
+        let started = Instant::now();
+        let deadline = command_deadline(started, spec.timeout.or(self.command_timeout), self.deadline);
+
+        let mut command = self.shell.wrap(spec.command, self.env);
+        if let Some(deadline) = deadline {
+            command = remote_timeout(&command, deadline.saturating_duration_since(started));
+        }
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&command)?;
+
+        self.session.set_blocking(false);
+        let streamed = self.stream_output(&mut channel, deadline);
+        self.session.set_blocking(true);
+
+        if !streamed? {
+            let _ = channel.close();
+            return Err(TransferError::CommandTimedOut {
+                command: spec.command.to_string(),
+                seconds: started.elapsed().as_secs(),
+            });
+        }
+        channel.wait_close()?;
+        Ok(channel.exit_status()?)
+    }
+
+    /// Copy stdout/stderr to our stdout until EOF, returns false if the deadline passed first
+    fn stream_output(
+        &self,
+        channel: &mut Channel,
+        deadline: Option<Instant>,
+    ) -> Result<bool, TransferError> {
+        let mut stdout = LinePrinter::new(format!("[{}]", self.host));
+        let mut stderr = LinePrinter::new(format!("[{} stderr]", self.host));
+        let mut stdout_done = false;
+        let mut stderr_done = false;
+        let mut buffer = [0u8; 8192];
+
+        while !(stdout_done && stderr_done) {
+            let mut progressed = false;
+            if !stdout_done {
+                match channel.read(&mut buffer) {
+                    Ok(0) => stdout_done = true,
+                    Ok(n) => {
+                        stdout.push(&buffer[..n]);
+                        progressed = true;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if !stderr_done {
+                match channel.stderr().read(&mut buffer) {
+                    Ok(0) => stderr_done = true,
+                    Ok(n) => {
+                        stderr.push(&buffer[..n]);
+                        progressed = true;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                stdout.flush();
+                stderr.flush();
+                return Ok(false);
+            }
+            if !progressed {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+
+        stdout.flush();
+        stderr.flush();
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_parse_command_spec() {
+    assert_eq!(
+        parse_command_spec("systemctl restart app"),
+        CommandSpec {
+            command: "systemctl restart app",
+            allow_failure: false,
+            timeout: None,
+        }
+    );
+    assert_eq!(
+        parse_command_spec("timeout=30: allow-failure: ./migrate.sh"),
+        CommandSpec {
+            command: "./migrate.sh",
+            allow_failure: true,
+            timeout: Some(Duration::from_secs(30)),
+        }
+    );
+    assert_eq!(parse_command_spec("timeout=abc: ls").command, "timeout=abc: ls");
+}
+
+#[cfg(test)]
+const TRICKY_COMMANDS: &[&str] = &[
+    "echo plain",
+    "echo \"double quoted $HOME\" | tr a-z A-Z",
+    "printf '%s\\n' 'single quoted' \"it's\"",
+    "echo back\\slash \\$HOME",
+    "echo `echo backticks` $(echo substitution)",
+    "x=1; echo $((x + 1)); echo ${x}",
+    "echo 'a;b' && echo c || echo d; echo e &",
+    "cat <<EOF\nheredoc $HOME\nEOF",
+    "echo '' \"\" '\"' \"'\"",
+    "echo !bang *glob? [x] ~ # comment",
+];
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("/srv/app/main.js"), "'/srv/app/main.js'");
+    assert_eq!(shell_quote("/srv/it's here"), "'/srv/it'\\''s here'");
+    assert_eq!(shell_quote(""), "''");
+}
+
+/// The wrapped command line, interpreted by a login shell, must run exactly the original command
+#[test]
+fn test_shell_wrap() {
+    let run = |shell: &str, command: &str| {
+        let output = std::process::Command::new(shell)
+            .arg("-c")
+            .arg(command)
+            .env("HOME", "/home/ci")
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    for command in TRICKY_COMMANDS {
+        let expected = run("bash", command);
+        assert_eq!(run("sh", &Shell::Bash.wrap(command, &[])), expected, "{}", command);
+        assert_eq!(run("bash", &Shell::Bash.wrap(command, &[])), expected, "{}", command);
+        assert_eq!(run("sh", &Shell::Sh.wrap(command, &[])), run("sh", command), "{}", command);
+        assert_eq!(Shell::None.wrap(command, &[]), *command);
+    }
+
+    let env = [("GREETING".to_string(), "it's $HOME `id`".to_string())];
+    let command = "echo \"$GREETING\"";
+    assert_eq!(run("sh", &Shell::Bash.wrap(command, &env)), "it's $HOME `id`\n");
+    assert_eq!(run("sh", &Shell::None.wrap(command, &env)), "it's $HOME `id`\n");
+}
+
+#[test]
+fn test_script_interpreter() {
+    let dir = std::env::temp_dir().join(format!("ci-transfer-test-interpreter-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let python = dir.join("migrate.py");
+    std::fs::write(&python, "#!/usr/bin/env python3 -u\nprint('hi')\n").unwrap();
+    let plain = dir.join("deploy.sh");
+    std::fs::write(&plain, "echo hi\n").unwrap();
+    assert_eq!(
+        script_interpreter(&python, Shell::Bash).unwrap(),
+        "'/usr/bin/env' 'python3' '-u'"
+    );
+    assert_eq!(script_interpreter(&plain, Shell::Bash).unwrap(), "bash");
+    assert_eq!(script_interpreter(&plain, Shell::None).unwrap(), "sh");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remote_timeout() {
+    assert_eq!(
+        remote_timeout("bash -c 'sleep 60'", Duration::from_millis(29_500)),
+        r#"timeout -k 5 31 sh -c 'bash -c '\''sleep 60'\'''"#
+    );
+    assert_eq!(remote_timeout("ls", Duration::from_secs(30)), "timeout -k 5 31 sh -c 'ls'");
+}
+
+#[test]
+fn test_execute_command_status() {
+    let runner = LocalRunner::new(None, None, Vec::new(), Shell::Sh);
+    assert_eq!(runner.execute("true").unwrap().status, 0);
+    match runner.execute("exit 3") {
+        Err(TransferError::CommandFailed { command, status }) => assert_eq!((command.as_str(), status), ("exit 3", 3)),
+        other => panic!("expected CommandFailed, got {:?}", other),
+    }
+    // Commands arrive decoded, one that happens to be valid base64 runs as it is ("exit 7" encoded)
+    match runner.execute("ZXhpdCA3") {
+        Err(TransferError::CommandFailed { status, .. }) => assert_eq!(status, 127),
+        other => panic!("expected CommandFailed, got {:?}", other),
+    }
+    // allow-failure: keeps the status but does not fail the run
+    let result = runner.execute("allow-failure: exit 4").unwrap();
+    assert_eq!((result.command.as_str(), result.status), ("exit 4", 4));
+}
@@ -0,0 +1,317 @@
+use clap::ArgEnum;
+use serde::Serialize;
+
+use crate::backend::{plan, DirLayout};
+use crate::command::decode_command;
+use crate::error::TransferError;
+use crate::filter::FileFilter;
+use crate::health::HealthCheck;
+use crate::jump::parse_jump_host;
+use crate::object_store::object_key;
+use crate::oss::parse_destination_oss;
+use crate::release::{release_name, Release};
+use crate::s3::parse_destination_s3;
+use crate::ssh::parse_destination_ssh;
+use crate::template::{command_env, TemplateVars};
+use crate::{Args, Command};
+
+/// Output format of `--dry-run`
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Debug)]
+struct FilePlan {
+    source: String,
+    /// Remote path, object key or local path the file is written to
+    target: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sidecars: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DestinationPlan {
+    kind: &'static str,
+    target: String,
+    action: &'static str,
+    /// Jump hosts the SSH connection goes through, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    via: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release: Option<String>,
+    /// Names of the variables exported to commands
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    env: Vec<String>,
+    precommands: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    prescripts: Vec<String>,
+    files: Vec<FilePlan>,
+    excluded: usize,
+    commands: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    scripts: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_url: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rollback_commands: Vec<String>,
+}
+
+impl DestinationPlan {
+    fn new(kind: &'static str, target: String) -> Self {
+        DestinationPlan {
+            kind,
+            target,
+            action: "transfer",
+            via: Vec::new(),
+            release: None,
+            env: Vec::new(),
+            precommands: Vec::new(),
+            prescripts: Vec::new(),
+            files: Vec::new(),
+            excluded: 0,
+            commands: Vec::new(),
+            scripts: Vec::new(),
+            health_url: None,
+            rollback_commands: Vec::new(),
+        }
+    }
+
+    /// Fill in the files of every source under `remote_root`, as object keys for object storage
+    fn add_files(&mut self, args: &Args, remote_root: &str) -> Result<(), TransferError> {
+        if args.source.is_empty() {
+            return Ok(());
+        }
+        let filter = FileFilter::from_args(args)?;
+        let object_store = matches!(self.kind, "oss" | "s3");
+        let layout = if object_store {
+            DirLayout::Contents
+        } else {
+            DirLayout::Named
+        };
+        for source in plan(args, remote_root, &filter, layout)? {
+            for job in source.files {
+                let target = if object_store {
+                    object_key(&job.remote)
+                } else {
+                    job.remote
+                };
+                let sidecars = if object_store {
+                    args.sidecar
+                        .iter()
+                        .map(|sidecar| format!("{}.{}", target, sidecar.extension()))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                self.files.push(FilePlan {
+                    source: job.local_path.display().to_string(),
+                    target,
+                    size: job.size,
+                    sidecars,
+                });
+            }
+        }
+        self.excluded = filter.excluded();
+        Ok(())
+    }
+
+    /// Pre- and post-transfer commands, decoded and rendered, for destinations that run commands
+    fn add_commands(&mut self, args: &Args, vars: &TemplateVars) -> Result<(), TransferError> {
+        let render = |commands: &[String]| {
+            commands
+                .iter()
+                .filter(|command| !command.is_empty())
+                .map(|command| vars.render(&decode_command(command)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        self.env = command_env(args)?.into_iter().map(|(name, _)| name).collect();
+        self.precommands = render(&args.precommands)?;
+        self.prescripts = render(&args.prescript)?;
+        self.commands = render(&args.commands)?;
+        self.scripts = render(&args.script)?;
+        if let Some(url) = &args.health_url {
+            // Reject a bad --health-body now, like a real run does before connecting
+            HealthCheck::from_args(args, vars)?;
+            self.health_url = Some(vars.render(url)?);
+            self.rollback_commands = render(&args.rollback_commands)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve what a run with `args` would do, without connecting anywhere
+fn build_plan(args: &Args) -> Result<Vec<DestinationPlan>, TransferError> {
+    let mut destinations = Vec::new();
+
+    if let Some(oss_dest) = &args.oss_destination {
+        let config = parse_destination_oss(oss_dest)?;
+        let mut destination = DestinationPlan::new("oss", config.target());
+        destination.add_files(args, config.path())?;
+        destinations.push(destination);
+    }
+
+    if let Some(s3_dest) = &args.s3_destination {
+        let config = parse_destination_s3(s3_dest)?;
+        let mut destination = DestinationPlan::new("s3", config.target());
+        destination.add_files(args, config.path())?;
+        destinations.push(destination);
+    }
+
+    if let Some(local_dest) = &args.local_destination {
+        let mut destination = DestinationPlan::new("local", local_dest.clone());
+        destination.add_commands(args, &TemplateVars::new(args, "localhost", local_dest, None))?;
+        destination.add_files(args, local_dest)?;
+        destinations.push(destination);
+    }
+
+    for ssh_dest in &args.destination {
+        let config = parse_destination_ssh(ssh_dest)?;
+        let mut destination = DestinationPlan::new("ssh", config.target());
+        for jump_host in &args.jump_host {
+            destination.via.push(parse_jump_host(jump_host)?.target());
+        }
+        let release = args
+            .release
+            .then(|| Release::new(config.remote_path(), &release_name(args)));
+        let remote_path = match &release {
+            Some(release) => release.upload_path(&args.source),
+            None => config.remote_path().to_string(),
+        };
+        destination.add_commands(args, &TemplateVars::new(args, config.host(), &remote_path, release.as_ref()))?;
+        if let Some(Command::Rollback { to }) = &args.command {
+            destination.action = "rollback";
+            destination.release = to.clone();
+            destination.precommands.clear();
+            destination.prescripts.clear();
+            destination.scripts.clear();
+            destination.health_url = None;
+            destination.rollback_commands.clear();
+        } else {
+            destination.release = release.as_ref().map(Release::dir);
+            destination.add_files(args, &remote_path)?;
+        }
+        destinations.push(destination);
+    }
+
+    Ok(destinations)
+}
+
+fn print_commands(stage: &str, commands: &[String]) {
+    if commands.is_empty() {
+        return;
+    }
+    println!("  {} commands:", stage);
+    for command in commands {
+        println!("    $ {}", command);
+    }
+}
+
+/// Print the files and commands of every destination instead of running them
+pub fn print_plan(args: &Args) -> Result<(), TransferError> {
+    let destinations = build_plan(args)?;
+    if args.plan_format == PlanFormat::Json {
+        let json = serde_json::to_string_pretty(&destinations).map_err(TransferError::JsonParseError)?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    println!("Dry run: nothing is transferred and no command is executed");
+    for destination in &destinations {
+        println!();
+        match (&destination.release, destination.action) {
+            (Some(release), "rollback") => {
+                println!("{} {} (rollback to {})", destination.kind, destination.target, release)
+            }
+            (None, "rollback") => println!("{} {} (rollback)", destination.kind, destination.target),
+            (Some(release), _) => println!("{} {} (release {})", destination.kind, destination.target, release),
+            (None, _) => println!("{} {}", destination.kind, destination.target),
+        }
+        if !destination.via.is_empty() {
+            println!("  via: {}", destination.via.join(" -> "));
+        }
+        if !destination.env.is_empty() {
+            println!("  env: {}", destination.env.join(", "));
+        }
+        print_commands("pre-transfer", &destination.precommands);
+        print_commands("pre-transfer script", &destination.prescripts);
+        if !destination.files.is_empty() {
+            println!("  files:");
+        }
+        for file in &destination.files {
+            println!("    {} -> {} ({} bytes)", file.source, file.target, file.size);
+            for sidecar in &file.sidecars {
+                println!("    + {}", sidecar);
+            }
+        }
+        if destination.excluded > 0 {
+            println!("  excluded {} file(s) by filters", destination.excluded);
+        }
+        let stage = if destination.action == "rollback" {
+            "post-rollback"
+        } else {
+            "post-transfer"
+        };
+        print_commands(stage, &destination.commands);
+        print_commands("post-transfer script", &destination.scripts);
+        if let Some(url) = &destination.health_url {
+            println!("  health check: {}", url);
+            print_commands("rollback", &destination.rollback_commands);
+        }
+    }
+
+    let files: usize = destinations.iter().map(|destination| destination.files.len()).sum();
+    let bytes: u64 = destinations
+        .iter()
+        .flat_map(|destination| &destination.files)
+        .map(|file| file.size)
+        .sum();
+    println!(
+        "\nTotal: {} file(s), {} bytes to {} destination(s)",
+        files,
+        bytes,
+        destinations.len()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_build_plan() {
+    use base64::{engine::general_purpose, Engine as _};
+    use clap::Parser;
+    let oss = r#"{"oss_bucket":"b","oss_endpoint":"e","key_secret":"s","key_id":"k","path":"/www/"}"#;
+    let command = general_purpose::STANDARD.encode("systemctl reload nginx");
+    let args = Args::parse_from([
+        "ci-transfer",
+        "-s",
+        "src/oss",
+        "-s",
+        "Cargo.toml",
+        "--oss-destination",
+        oss,
+        "--sidecar",
+        "md5",
+        "-d",
+        "deploy:secret@10.0.0.1:/srv/app",
+        "--commands",
+        &command,
+    ]);
+    let destinations = build_plan(&args).unwrap();
+
+    let oss = &destinations[0];
+    assert_eq!(oss.target, "oss://b/www/");
+    assert!(oss.commands.is_empty());
+    let manifest = oss.files.iter().find(|file| file.source == "Cargo.toml").unwrap();
+    assert_eq!(manifest.target, "/www/Cargo.toml");
+    assert_eq!(manifest.sidecars, ["/www/Cargo.toml.md5"]);
+    // Object stores put the contents of a directory source straight under the path
+    assert!(oss.files.iter().any(|file| file.target == "/www/multipart.rs"));
+
+    let ssh = &destinations[1];
+    assert_eq!(ssh.target, "deploy@10.0.0.1:/srv/app");
+    assert_eq!(ssh.commands, ["systemctl reload nginx"]);
+    assert!(ssh.files.iter().any(|file| file.target == "/srv/app/Cargo.toml"));
+    assert!(ssh.files.iter().any(|file| file.target == "/srv/app/oss/multipart.rs"));
+}
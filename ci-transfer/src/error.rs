@@ -3,7 +3,35 @@ pub enum TransferError {
     IoError(std::io::Error),
     SshError(ssh2::Error),
     JsonParseError(serde_json::Error),
+    ManifestError(String),
     OssError(String),
+    S3Error(String),
+    HostKeyMismatch {
+        host: String,
+        expected: String,
+        actual: String,
+    },
+    HostKeyUnknown {
+        host: String,
+        fingerprint: String,
+    },
+    CommandFailed {
+        command: String,
+        status: i32,
+    },
+    CommandTimedOut {
+        command: String,
+        seconds: u64,
+    },
+    ChecksumMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    HealthCheckFailed {
+        url: String,
+        reason: String,
+    },
     Other(String),
 }
 
@@ -26,7 +54,45 @@ impl std::fmt::Display for TransferError {
             TransferError::SshError(e) => write!(f, "SSH Error: {}", e),
             TransferError::Other(s) => write!(f, "Other Error: {}", s),
             TransferError::OssError(e) => write!(f, "OSS Error: {}", e),
+            TransferError::S3Error(e) => write!(f, "S3 Error: {}", e),
             TransferError::JsonParseError(e) => write!(f, "JSON Parse Error: {}", e),
+            TransferError::ManifestError(e) => write!(f, "Manifest Error: {}", e),
+            TransferError::HostKeyMismatch {
+                host,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Host Key Mismatch: {} presented {}, expected {}",
+                host, actual, expected
+            ),
+            TransferError::HostKeyUnknown { host, fingerprint } => write!(
+                f,
+                "Host Key Unknown: {} presented {}, pin it with --host-key-fingerprint or pass --trust-on-first-use",
+                host, fingerprint
+            ),
+            TransferError::CommandFailed { command, status } => write!(
+                f,
+                "Command Failed: `{}` exited with status {}",
+                command, status
+            ),
+            TransferError::CommandTimedOut { command, seconds } => write!(
+                f,
+                "Command Timed Out: `{}` was killed after {}s",
+                command, seconds
+            ),
+            TransferError::ChecksumMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum Mismatch: {} has {}, expected {}",
+                path, actual, expected
+            ),
+            TransferError::HealthCheckFailed { url, reason } => {
+                write!(f, "Health Check Failed: {} is not healthy: {}", url, reason)
+            }
         }
     }
 }
\ No newline at end of file
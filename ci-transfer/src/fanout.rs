@@ -0,0 +1,207 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::backend::TransferStats;
+use crate::error::TransferError;
+use crate::ssh::{handle_ssh, SshConfig};
+use crate::Args;
+
+/// Outcome of deploying to one SSH host (or another destination, in the --report)
+pub enum HostOutcome {
+    Success(TransferStats),
+    /// The error, with whatever was transferred and run before it
+    Failed(String, TransferStats),
+    /// Not attempted because an earlier rolling batch failed
+    Skipped,
+}
+
+impl HostOutcome {
+    pub fn new(result: Result<(), TransferError>, stats: TransferStats) -> Self {
+        match result {
+            Ok(()) => HostOutcome::Success(stats),
+            Err(e) => HostOutcome::Failed(e.to_string(), stats),
+        }
+    }
+}
+
+pub struct HostResult {
+    pub host: String,
+    pub outcome: HostOutcome,
+    pub duration: Duration,
+}
+
+fn deploy_host(args: &Args, ssh_config: SshConfig) -> HostResult {
+    let host = ssh_config.host().to_string();
+    let started = Instant::now();
+    let mut stats = TransferStats::default();
+    let result = handle_ssh(args, ssh_config, &mut stats);
+    HostResult {
+        host,
+        outcome: HostOutcome::new(result, stats),
+        duration: started.elapsed(),
+    }
+}
+
+/// Deploy to every host with `deploy` with at most `parallel` hosts in flight, regardless of failures
+fn deploy_parallel<F>(configs: Vec<SshConfig>, parallel: usize, deploy: &F) -> Vec<HostResult>
+where
+    F: Fn(SshConfig) -> HostResult + Sync,
+{
+    let total = configs.len();
+    let queue: Vec<Mutex<Option<SshConfig>>> =
+        configs.into_iter().map(|config| Mutex::new(Some(config))).collect();
+    let results: Vec<Mutex<Option<HostResult>>> = (0..total).map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+
+    std::thread::scope(|scope| {
+        for _ in 0..parallel.clamp(1, total.max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= total {
+                    break;
+                }
+                let config = queue[index].lock().unwrap().take().unwrap();
+                let result = deploy(config);
+                *results[index].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+/// Deploy batch by batch, skipping the remaining batches once a host has failed
+fn deploy_rolling<F>(configs: Vec<SshConfig>, batch_size: usize, deploy: &F) -> Vec<HostResult>
+where
+    F: Fn(SshConfig) -> HostResult + Sync,
+{
+    let mut results = Vec::new();
+    let mut pending = configs.into_iter().peekable();
+    let mut failed = false;
+
+    while pending.peek().is_some() {
+        let batch: Vec<SshConfig> = pending.by_ref().take(batch_size.max(1)).collect();
+        if failed {
+            results.extend(batch.iter().map(|config| HostResult {
+                host: config.host().to_string(),
+                outcome: HostOutcome::Skipped,
+                duration: Duration::ZERO,
+            }));
+            continue;
+        }
+        println!(
+            "Rolling deploy batch: {}",
+            batch.iter().map(SshConfig::host).collect::<Vec<_>>().join(", ")
+        );
+        let batch_len = batch.len();
+        let batch_results = deploy_parallel(batch, batch_len, deploy);
+        failed = batch_results
+            .iter()
+            .any(|result| matches!(result.outcome, HostOutcome::Failed(..)));
+        results.extend(batch_results);
+    }
+
+    results
+}
+
+/// Deploy to all SSH destinations, in rolling batches if --rolling is set
+pub fn deploy_hosts(args: &Args, configs: Vec<SshConfig>) -> Vec<HostResult> {
+    let deploy = |config| deploy_host(args, config);
+    match args.rolling {
+        Some(batch_size) => deploy_rolling(configs, batch_size, &deploy),
+        None => deploy_parallel(configs, args.parallel, &deploy),
+    }
+}
+
+pub fn print_summary(results: &[HostResult]) {
+    let host_width = results
+        .iter()
+        .map(|result| result.host.len())
+        .max()
+        .unwrap_or(0)
+        .max("HOST".len());
+    println!();
+    println!(
+        "{:<host_width$}  {:<8}  {:>9}  {:>8}  {:>7}  {:>7}  ERROR",
+        "HOST", "RESULT", "DURATION", "UPLOADED", "SKIPPED", "DELETED"
+    );
+    for result in results {
+        let duration = format!("{:.1}s", result.duration.as_secs_f64());
+        match &result.outcome {
+            HostOutcome::Success(stats) => println!(
+                "{:<host_width$}  {:<8}  {:>9}  {:>8}  {:>7}  {:>7}",
+                result.host, "ok", duration, stats.uploaded, stats.skipped, stats.deleted
+            ),
+            HostOutcome::Failed(error, stats) => println!(
+                "{:<host_width$}  {:<8}  {:>9}  {:>8}  {:>7}  {:>7}  {}",
+                result.host, "failed", duration, stats.uploaded, stats.skipped, stats.deleted, error
+            ),
+            HostOutcome::Skipped => println!(
+                "{:<host_width$}  {:<8}  {:>9}  {:>8}  {:>7}  {:>7}",
+                result.host, "skipped", "-", "-", "-", "-"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_configs(count: usize) -> Vec<SshConfig> {
+    (1..=count)
+        .map(|i| crate::ssh::parse_destination_ssh(&format!("deploy@10.0.0.{}:/srv/app", i)).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+fn test_result(config: &SshConfig, outcome: HostOutcome) -> HostResult {
+    HostResult {
+        host: config.host().to_string(),
+        outcome,
+        duration: Duration::ZERO,
+    }
+}
+
+#[test]
+fn test_deploy_rolling_skips_after_failure() {
+    let deployed = AtomicUsize::new(0);
+    let results = deploy_rolling(test_configs(5), 2, &|config: SshConfig| {
+        deployed.fetch_add(1, Ordering::SeqCst);
+        let outcome = if config.host() == "10.0.0.2" {
+            HostOutcome::Failed("Command Failed".into(), TransferStats::default())
+        } else {
+            HostOutcome::Success(TransferStats::default())
+        };
+        test_result(&config, outcome)
+    });
+
+    // Only the first batch ran, the other three hosts are reported in order as skipped
+    assert_eq!(deployed.load(Ordering::SeqCst), 2);
+    let hosts: Vec<&str> = results.iter().map(|result| result.host.as_str()).collect();
+    assert_eq!(hosts, ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4", "10.0.0.5"]);
+    assert!(matches!(results[0].outcome, HostOutcome::Success(_)));
+    assert!(matches!(results[1].outcome, HostOutcome::Failed(..)));
+    assert!(results[2..]
+        .iter()
+        .all(|result| matches!(result.outcome, HostOutcome::Skipped)));
+}
+
+#[test]
+fn test_deploy_parallel_limit() {
+    let in_flight = AtomicUsize::new(0);
+    let most = AtomicUsize::new(0);
+    let results = deploy_parallel(test_configs(8), 3, &|config: SshConfig| {
+        let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        most.fetch_max(now, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+        test_result(&config, HostOutcome::Success(TransferStats::default()))
+    });
+
+    // Never more than --parallel, but more than one at a time
+    assert!((2..=3).contains(&most.load(Ordering::SeqCst)));
+    assert_eq!(results.len(), 8);
+    assert_eq!(results[7].host, "10.0.0.8");
+}
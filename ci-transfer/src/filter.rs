@@ -0,0 +1,128 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::cell::Cell;
+use std::fs::read_dir;
+use std::path::Path;
+
+use crate::error::TransferError;
+use crate::Args;
+
+/// Ignore file read from the working directory when --ignore-file is not given
+const DEFAULT_IGNORE_FILE: &str = ".transferignore";
+
+/// Gitignore-style include/exclude rules applied to files found inside source directories
+pub struct FileFilter {
+    excludes: Gitignore,
+    includes: Option<Gitignore>,
+    excluded: Cell<usize>,
+}
+
+fn build_matcher(patterns: &[String], ignore_file: Option<&Path>) -> Result<Gitignore, TransferError> {
+    let mut builder = GitignoreBuilder::new("");
+    if let Some(path) = ignore_file {
+        if let Some(e) = builder.add(path) {
+            return Err(TransferError::Other(format!(
+                "Invalid ignore file {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    }
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .map_err(|e| TransferError::Other(format!("Invalid pattern {}: {}", pattern, e)))?;
+    }
+    builder
+        .build()
+        .map_err(|e| TransferError::Other(format!("Invalid filter patterns: {}", e)))
+}
+
+fn count_files(dir: &Path) -> usize {
+    read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| {
+                    let path = entry.path();
+                    if path.is_dir() {
+                        count_files(&path)
+                    } else {
+                        1
+                    }
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+impl FileFilter {
+    pub fn new(
+        includes: &[String],
+        excludes: &[String],
+        ignore_file: Option<&Path>,
+    ) -> Result<Self, TransferError> {
+        Ok(FileFilter {
+            excludes: build_matcher(excludes, ignore_file)?,
+            includes: if includes.is_empty() {
+                None
+            } else {
+                Some(build_matcher(includes, None)?)
+            },
+            excluded: Cell::new(0),
+        })
+    }
+
+    /// Filter from --include/--exclude and --ignore-file, falling back to ./.transferignore
+    pub fn from_args(args: &Args) -> Result<Self, TransferError> {
+        let ignore_file = match &args.ignore_file {
+            Some(path) => Some(Path::new(path)),
+            None => Some(Path::new(DEFAULT_IGNORE_FILE)).filter(|path| path.is_file()),
+        };
+        FileFilter::new(&args.include, &args.exclude, ignore_file)
+    }
+
+    /// Whether `path` (found below the source directory `root`) should be transferred.
+    /// Excluded directories are counted by the number of files they contain.
+    pub fn allows(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        if self.excludes.matched(relative, is_dir).is_ignore() {
+            let files = if is_dir { count_files(path) } else { 1 };
+            self.excluded.set(self.excluded.get() + files);
+            return false;
+        }
+        if let Some(includes) = &self.includes {
+            if !is_dir && !includes.matched_path_or_any_parents(relative, false).is_ignore() {
+                self.excluded.set(self.excluded.get() + 1);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether a remote-only path would have been excluded, used to protect it from --delete
+    pub fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        self.excludes.matched(relative, is_dir).is_ignore()
+    }
+
+    pub fn excluded(&self) -> usize {
+        self.excluded.get()
+    }
+}
+
+#[test]
+fn test_file_filter() {
+    let filter = FileFilter::new(
+        &["*.js".to_string(), "assets/".to_string()],
+        &[".git".to_string(), "*.map".to_string()],
+        None,
+    )
+    .unwrap();
+    let root = Path::new("dist");
+    assert!(filter.allows(root, Path::new("dist/app.js"), false));
+    assert!(filter.allows(root, Path::new("dist/assets/logo.png"), false));
+    assert!(filter.allows(root, Path::new("dist/assets"), true));
+    assert!(!filter.allows(root, Path::new("dist/app.js.map"), false));
+    assert!(!filter.allows(root, Path::new("dist/index.html"), false));
+    assert!(!filter.allows(root, Path::new("dist/sub/.git"), true));
+    assert_eq!(filter.excluded(), 2);
+}
@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+use clap::ArgEnum;
+use regex::Regex;
+use serde::Deserialize;
+use ssh2::Session;
+
+use crate::command::shell_quote;
+use crate::error::TransferError;
+use crate::ssh::exec_remote;
+use crate::template::TemplateVars;
+use crate::Args;
+
+/// Where --health-url is requested from
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthFrom {
+    /// The machine running ci-transfer
+    Runner,
+    /// The SSH destination, with curl
+    Host,
+}
+
+/// Polls a URL after the commands finished until it answers as expected or the timeout passes
+pub struct HealthCheck {
+    url: String,
+    status: Option<u16>,
+    body: Option<Regex>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl HealthCheck {
+    /// The check configured by --health-url, with `{{...}}` variables of the destination filled in
+    pub fn from_args(args: &Args, vars: &TemplateVars) -> Result<Option<Self>, TransferError> {
+        let Some(url) = &args.health_url else {
+            return Ok(None);
+        };
+        let body = match &args.health_body {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| TransferError::Other(format!("Invalid --health-body regex: {}", e)))?,
+            ),
+            None => None,
+        };
+        Ok(Some(HealthCheck {
+            url: vars.render(url)?,
+            status: args.health_status,
+            body,
+            interval: Duration::from_secs(args.health_interval),
+            timeout: Duration::from_secs(args.health_timeout),
+        }))
+    }
+
+    /// Why a response does not count as healthy: another status than --health-status (any 2xx by
+    /// default), or a body not matching --health-body
+    fn problem(&self, status: u16, body: &str) -> Option<String> {
+        let status_ok = match self.status {
+            Some(expected) => status == expected,
+            None => (200..300).contains(&status),
+        };
+        if !status_ok {
+            return Some(format!("status {}", status));
+        }
+        match &self.body {
+            Some(pattern) if !pattern.is_match(body) => Some(format!("body does not match /{}/", pattern)),
+            _ => None,
+        }
+    }
+
+    /// Request the URL with `probe` every interval until it is healthy
+    fn wait<F>(&self, probe: F) -> Result<(), TransferError>
+    where
+        F: Fn(&str) -> Result<(u16, String), String>,
+    {
+        println!("Health check: {}", self.url);
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let problem = match probe(&self.url) {
+                Ok((status, body)) => self.problem(status, &body),
+                Err(e) => Some(e),
+            };
+            let Some(problem) = problem else {
+                println!("Health check passed after {} attempt(s)", attempt);
+                return Ok(());
+            };
+            println!("Health check attempt {}: {}", attempt, problem);
+            if started.elapsed() + self.interval > self.timeout {
+                return Err(TransferError::HealthCheckFailed {
+                    url: self.url.clone(),
+                    reason: format!("{} after {}s", problem, started.elapsed().as_secs()),
+                });
+            }
+            std::thread::sleep(self.interval);
+            attempt += 1;
+        }
+    }
+
+    /// Poll from the machine running ci-transfer
+    pub fn wait_from_runner(&self) -> Result<(), TransferError> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(self.interval.max(Duration::from_secs(5)))
+            .build()
+            .map_err(|e| TransferError::Other(e.to_string()))?;
+        self.wait(|url| {
+            let response = client.get(url).send().map_err(|e| e.to_string())?;
+            let status = response.status().as_u16();
+            Ok((status, response.text().unwrap_or_default()))
+        })
+    }
+
+    /// Poll from the SSH host with curl, for URLs only reachable there (e.g. localhost)
+    pub fn wait_from_host(&self, session: &Session) -> Result<(), TransferError> {
+        self.wait(|url| {
+            let command = format!(
+                "curl -sS --max-time {} -w '\\n%{{http_code}}' {}",
+                self.interval.as_secs().max(5),
+                shell_quote(url)
+            );
+            let (output, status) = exec_remote(session, &command).map_err(|e| e.to_string())?;
+            parse_curl_output(&output).ok_or_else(|| format!("curl exited with status {}", status))
+        })
+    }
+}
+
+/// Split curl output written with `-w '\n%{http_code}'` into status and body
+fn parse_curl_output(output: &str) -> Option<(u16, String)> {
+    let (body, status) = output.trim_end().rsplit_once('\n').unwrap_or(("", output.trim_end()));
+    match status.trim().parse::<u16>() {
+        Ok(0) | Err(_) => None,
+        Ok(status) => Some((status, body.to_string())),
+    }
+}
+
+#[test]
+fn test_health_check() {
+    use clap::Parser;
+    use std::cell::Cell;
+    let args = Args::parse_from([
+        "ci-transfer",
+        "--health-url",
+        "http://{{host}}:8080/health",
+        "--health-body",
+        r#""status":\s*"UP""#,
+        "--health-interval",
+        "0",
+        "--health-timeout",
+        "1",
+    ]);
+    let vars = TemplateVars::new(&args, "10.0.0.1", "/srv/app", None);
+    let check = HealthCheck::from_args(&args, &vars).unwrap().unwrap();
+    assert_eq!(check.url, "http://10.0.0.1:8080/health");
+    assert_eq!(check.problem(503, "").as_deref(), Some("status 503"));
+    assert!(check.problem(200, r#"{"status":"DOWN"}"#).is_some());
+
+    let attempts = Cell::new(0);
+    check
+        .wait(|_| {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 => Err("connection refused".into()),
+                2 => Ok((502, String::new())),
+                _ => Ok((200, r#"{"status": "UP"}"#.into())),
+            }
+        })
+        .unwrap();
+    assert_eq!(attempts.get(), 3);
+
+    assert_eq!(parse_curl_output("ok\n200"), Some((200, "ok".to_string())));
+    assert_eq!(parse_curl_output("\n000"), None);
+}
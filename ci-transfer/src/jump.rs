@@ -0,0 +1,170 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine as _};
+use ssh2::{Channel, Session};
+
+use crate::error::TransferError;
+use crate::mask::add_secret;
+
+/// One hop of --jump-host, connected to in order like OpenSSH ProxyJump
+#[derive(Debug, PartialEq, Eq)]
+pub struct JumpHost {
+    pub username: String,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: u16,
+    /// Private key of this hop, tried before the key and agent options of the destination
+    pub key_file: Option<String>,
+}
+
+/// Parse `user[:pass]@host[:port][?key=/path/to/key]`, or the same base64 encoded
+pub fn parse_jump_host(jump_host: &str) -> Result<JumpHost, TransferError> {
+    if let Ok(decoded) = general_purpose::STANDARD.decode(jump_host) {
+        if let Ok(s) = std::str::from_utf8(&decoded) {
+            add_secret(jump_host);
+            return parse_jump_host(s);
+        }
+    }
+    let invalid = || {
+        TransferError::Other("Invalid jump host format, expected user[:pass]@host[:port][?key=/path]".into())
+    };
+    let (jump_host, key_file) = match jump_host.split_once('?') {
+        Some((jump_host, query)) => match query.strip_prefix("key=") {
+            Some(key_file) if !key_file.is_empty() => (jump_host, Some(key_file.to_string())),
+            _ => return Err(invalid()),
+        },
+        None => (jump_host, None),
+    };
+    let parts: Vec<&str> = jump_host.split('@').collect();
+    if parts.len() != 2 {
+        return Err(invalid());
+    }
+
+    let credentials: Vec<&str> = parts[0].split(':').collect();
+    if credentials.len() > 2 || credentials[0].is_empty() {
+        return Err(invalid());
+    }
+    if let Some(password) = credentials.get(1) {
+        add_secret(password);
+    }
+
+    let (host, port) = match parts[1].split_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+        None => (parts[1], 22),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(JumpHost {
+        username: credentials[0].to_string(),
+        password: credentials.get(1).map(|p| p.to_string()),
+        host: host.to_string(),
+        port,
+        key_file,
+    })
+}
+
+impl JumpHost {
+    /// `user@host:port`, without the password
+    pub fn target(&self) -> String {
+        format!("{}@{}:{}", self.username, self.host, self.port)
+    }
+}
+
+/// Open a direct-tcpip channel from `session` to `host:port` and expose it as a local socket.
+/// libssh2 only runs a session over a real socket, so a pump thread copies between the channel
+/// and one end of a loopback connection; the other end is returned for `Session::set_tcp_stream`.
+/// The thread owns `session` and closes it once either side hangs up.
+pub fn tunnel(session: Session, host: &str, port: u16) -> Result<TcpStream, TransferError> {
+    let channel = session.channel_direct_tcpip(host, port, None)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, peer) = listener.accept()?;
+    // Nothing but our own connection may become the tunnel
+    if peer != client.local_addr()? {
+        return Err(TransferError::Other("Unexpected connection to the jump host tunnel".into()));
+    }
+    std::thread::spawn(move || pump(session, channel, server));
+    Ok(client)
+}
+
+/// Write all of `data`, waiting while a non-blocking writer is full
+fn write_all_nonblocking(writer: &mut impl Write, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => data = &data[written..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(1)),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copy between the channel and the local socket in both directions until either side closes.
+/// Both are non-blocking, as a blocking read on the channel would hold the session lock.
+fn pump(session: Session, mut channel: Channel, mut stream: TcpStream) {
+    session.set_blocking(false);
+    if stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut buffer = vec![0u8; 32 * 1024];
+    loop {
+        let mut idle = true;
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                if write_all_nonblocking(&mut channel, &buffer[..read]).is_err() {
+                    break;
+                }
+                idle = false;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+        match channel.read(&mut buffer) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => {}
+            Ok(read) => {
+                if write_all_nonblocking(&mut stream, &buffer[..read]).is_err() {
+                    break;
+                }
+                idle = false;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+        if idle {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    let _ = channel.close();
+}
+
+#[test]
+fn test_parse_jump_host() {
+    assert_eq!(
+        parse_jump_host("ops:s3cret@bastion.example.com:2222").unwrap(),
+        JumpHost {
+            username: "ops".into(),
+            password: Some("s3cret".into()),
+            host: "bastion.example.com".into(),
+            port: 2222,
+            key_file: None,
+        }
+    );
+    let jump_host = parse_jump_host("ops@bastion.example.com?key=/home/ci/.ssh/bastion_ed25519").unwrap();
+    assert_eq!(jump_host.key_file.as_deref(), Some("/home/ci/.ssh/bastion_ed25519"));
+    assert_eq!(jump_host.port, 22);
+    assert!(parse_jump_host("ops@10.0.0.1?user=root").is_err());
+    let jump_host = parse_jump_host("ops@10.0.0.1").unwrap();
+    assert_eq!((jump_host.password, jump_host.port), (None, 22));
+    let encoded = general_purpose::STANDARD.encode("ops@10.0.0.1:22");
+    assert_eq!(parse_jump_host(&encoded).unwrap().host, "10.0.0.1");
+    assert!(parse_jump_host("10.0.0.1").is_err());
+    assert!(parse_jump_host("ops@10.0.0.1:ssh").is_err());
+}
@@ -0,0 +1,320 @@
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::backend::{run_commands, run_scripts, transfer, Backend, FileJob, Progress, SourcePlan, TransferStats};
+use crate::command::{script_interpreter, shell_quote, CommandResult, LocalRunner};
+use crate::error::TransferError;
+use crate::filter::FileFilter;
+use crate::health::HealthCheck;
+use crate::ssh::{local_sha256, mtime_secs};
+use crate::template::{command_env, TemplateVars};
+use crate::Args;
+
+/// Copies into a directory on this machine, e.g. an NFS mount, and runs commands locally
+pub struct LocalBackend<'a> {
+    args: &'a Args,
+    runner: LocalRunner,
+}
+
+impl<'a> LocalBackend<'a> {
+    pub fn new(args: &'a Args, runner: LocalRunner) -> Self {
+        LocalBackend { args, runner }
+    }
+
+    /// Same size and mtime, or same SHA-256 with --checksum
+    fn is_unchanged(&self, local_path: &Path, target: &Path) -> Result<bool, TransferError> {
+        let target_meta = match target.metadata() {
+            Ok(meta) => meta,
+            Err(_) => return Ok(false),
+        };
+        let local_meta = local_path.metadata()?;
+        if target_meta.len() != local_meta.len() {
+            return Ok(false);
+        }
+        if self.args.checksum {
+            return Ok(local_sha256(local_path)? == local_sha256(target)?);
+        }
+        Ok(mtime_secs(&target_meta) == mtime_secs(&local_meta))
+    }
+
+    /// Remove entries of `target_dir` which no longer exist in `local_dir`
+    fn delete_extraneous(
+        &self,
+        local_dir: &Path,
+        target_dir: &Path,
+        root: &Path,
+        filter: &FileFilter,
+    ) -> Result<usize, TransferError> {
+        let mut deleted = 0;
+        for entry in fs::read_dir(target_dir)? {
+            let target_path = entry?.path();
+            let local_path = local_dir.join(target_path.file_name().unwrap());
+            if local_path.exists() {
+                continue;
+            }
+            let is_dir = target_path.is_dir();
+            let relative = local_path.strip_prefix(root).unwrap_or(&local_path);
+            if filter.is_excluded(relative, is_dir) {
+                continue;
+            }
+            deleted += remove_path(&target_path, is_dir)?;
+        }
+        Ok(deleted)
+    }
+}
+
+fn remove_path(path: &Path, is_dir: bool) -> Result<usize, TransferError> {
+    let mut deleted = 0;
+    if is_dir {
+        for entry in fs::read_dir(path)? {
+            let child = entry?.path();
+            let child_is_dir = child.is_dir();
+            deleted += remove_path(&child, child_is_dir)?;
+        }
+        fs::remove_dir(path)?;
+    } else {
+        fs::remove_file(path)?;
+        deleted += 1;
+    }
+    println!("Deleted: {}", path.display());
+    Ok(deleted)
+}
+
+impl Backend for LocalBackend<'_> {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn concurrency(&self) -> usize {
+        self.args.parallel
+    }
+
+    fn make_dir(&self, remote_dir: &str) -> Result<(), TransferError> {
+        let path = Path::new(remote_dir);
+        if !path.is_dir() {
+            fs::create_dir(path)?;
+        }
+        Ok(())
+    }
+
+    fn put_file(&self, job: &FileJob, progress: &Progress) -> Result<Option<&'static str>, TransferError> {
+        let target = Path::new(&job.remote);
+        if self.args.sync && self.is_unchanged(&job.local_path, target)? {
+            return Ok(Some("unchanged"));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&job.local_path, target)?;
+        if self.args.sync {
+            // Keep the source mtime so the next run can skip the file
+            let modified = job.local_path.metadata()?.modified()?;
+            File::options().write(true).open(target)?.set_modified(modified)?;
+        }
+        if self.args.verify {
+            let expected = local_sha256(&job.local_path)?;
+            let actual = local_sha256(target)?;
+            if actual != expected {
+                return Err(TransferError::ChecksumMismatch {
+                    path: job.remote.clone(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+        progress.add_bytes(job.size);
+        Ok(None)
+    }
+
+    fn run_command(&self, command: &str) -> Result<CommandResult, TransferError> {
+        self.runner.execute(command)
+    }
+
+    /// Run a copy of the script in a private temp directory through its interpreter, like SSH runs
+    /// its upload, and remove the directory afterwards
+    fn run_script(&self, script: &Path, options: &str, arguments: &str) -> Result<CommandResult, TransferError> {
+        // A fresh directory, failing rather than following anything planted in the temp dir
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or(0);
+        let dir = std::env::temp_dir().join(format!("ci-transfer-{}-{}", std::process::id(), nanos));
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir)?;
+        let copy = dir.join(script.file_name().unwrap());
+        let result = fs::copy(script, &copy).map_err(TransferError::from).and_then(|_| {
+            self.runner.execute(&format!(
+                "{}{} {} {}",
+                options,
+                script_interpreter(script, self.args.shell)?,
+                shell_quote(&copy.to_string_lossy()),
+                arguments
+            ))
+        });
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            println!("Warning: could not remove script directory {}: {}", dir.display(), e);
+        }
+        result
+    }
+
+    fn finish_tree(&self, source: &SourcePlan, filter: &FileFilter) -> Result<usize, TransferError> {
+        if !self.args.delete {
+            return Ok(0);
+        }
+        let mut deleted = 0;
+        for (local_dir, target_dir) in &source.dirs {
+            deleted += self.delete_extraneous(local_dir, Path::new(target_dir), &source.local, filter)?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Transfer to the local `target` directory, adding what was done to `stats` even when it fails
+pub fn handle_local(args: &Args, target: &str, stats: &mut TransferStats) -> Result<(), TransferError> {
+    let deadline = args
+        .timeout
+        .map(|seconds| Instant::now() + std::time::Duration::from_secs(seconds));
+    let runner = LocalRunner::new(args.command_timeout, deadline, command_env(args)?, args.shell);
+    let backend = LocalBackend::new(args, runner);
+    let vars = TemplateVars::new(args, "localhost", target, None);
+    let health_check = HealthCheck::from_args(args, &vars)?;
+    run_commands(&backend, "pre-transfer", &args.precommands, &vars, stats)?;
+    run_scripts(&backend, "pre-transfer", &args.prescript, &vars, stats)?;
+    transfer(&backend, args, target, stats)?;
+    run_commands(&backend, "post-transfer", &args.commands, &vars, stats)?;
+    run_scripts(&backend, "post-transfer", &args.script, &vars, stats)?;
+
+    // The runner is the host here, so --health-from makes no difference
+    if let Some(check) = &health_check {
+        if let Err(e) = check.wait_from_runner() {
+            println!("\n{}, running rollback commands", e);
+            run_commands(&backend, "rollback", &args.rollback_commands, &vars, stats)?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_tree(name: &str) -> std::path::PathBuf {
+    let root = std::env::temp_dir().join(format!("ci-transfer-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("dist/assets")).unwrap();
+    fs::write(root.join("dist/index.html"), "index").unwrap();
+    fs::write(root.join("dist/app.js.map"), "map").unwrap();
+    fs::write(root.join("dist/assets/app.js"), "app").unwrap();
+    fs::write(root.join("app.jar"), "jar").unwrap();
+    fs::create_dir_all(root.join("out")).unwrap();
+    root
+}
+
+#[cfg(test)]
+fn test_backend(args: &Args, env: Vec<(String, String)>) -> LocalBackend<'_> {
+    use crate::command::Shell;
+    LocalBackend::new(args, LocalRunner::new(None, None, env, Shell::Bash))
+}
+
+#[test]
+fn test_local_transfer_mapping() {
+    use clap::Parser;
+    let root = test_tree("mapping");
+    let source = |name: &str| root.join(name).to_string_lossy().into_owned();
+    let out = |path: &str| root.join("out").join(path);
+
+    // One file to a file path, one directory to a path without trailing slash
+    let args = Args::parse_from(["ci-transfer", "-s", &source("app.jar")]);
+    let mut stats = TransferStats::default();
+    transfer(&test_backend(&args, Vec::new()), &args, &out("app-1.jar").to_string_lossy(), &mut stats).unwrap();
+    assert!(out("app-1.jar").is_file());
+    let args = Args::parse_from(["ci-transfer", "-s", &source("dist"), "--exclude", "*.map"]);
+    let target = out("site").to_string_lossy().into_owned();
+    let mut stats = TransferStats::default();
+    transfer(&test_backend(&args, Vec::new()), &args, &target, &mut stats).unwrap();
+    assert_eq!(stats.uploaded, 2);
+    assert!(out("site/assets/app.js").is_file());
+    assert!(!out("site/app.js.map").exists());
+
+    // Trailing slash or several sources: every source keeps its name
+    let target = format!("{}/", out("").to_string_lossy().trim_end_matches('/'));
+    let args = Args::parse_from(["ci-transfer", "-s", &source("dist"), "-s", &source("app.jar")]);
+    transfer(&test_backend(&args, Vec::new()), &args, &target, &mut TransferStats::default()).unwrap();
+    assert!(out("dist/index.html").is_file());
+    assert!(out("app.jar").is_file());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_local_sync_delete() {
+    use clap::Parser;
+    let root = test_tree("sync");
+    let target = root.join("out/site").to_string_lossy().into_owned();
+    let args = Args::parse_from([
+        "ci-transfer",
+        "-s",
+        &root.join("dist").to_string_lossy(),
+        "--sync",
+        "--delete",
+        "--exclude",
+        "*.log",
+    ]);
+    let backend = test_backend(&args, Vec::new());
+    let mut stats = TransferStats::default();
+    transfer(&backend, &args, &target, &mut stats).unwrap();
+    assert_eq!(stats.uploaded, 3);
+
+    fs::write(root.join("dist/index.html"), "changed").unwrap();
+    fs::remove_file(root.join("dist/app.js.map")).unwrap();
+    fs::write(root.join("out/site/server.log"), "log").unwrap();
+    let mut stats = TransferStats::default();
+    transfer(&backend, &args, &target, &mut stats).unwrap();
+    assert_eq!((stats.uploaded, stats.skipped, stats.deleted), (1, 1, 1));
+    assert!(root.join("out/site/server.log").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_local_scripts() {
+    use clap::Parser;
+    let root = test_tree("scripts");
+    let script = root.join("deploy.sh");
+    let output = root.join("out/script.txt");
+    fs::write(&script, "echo \"$1 $2 $MODE\" > \"$OUTPUT\"\nexit 3\n").unwrap();
+    let args = Args::parse_from(["ci-transfer", "--env", "MODE=prod"]);
+    let env = vec![
+        ("MODE".to_string(), "prod".to_string()),
+        ("OUTPUT".to_string(), output.to_string_lossy().into_owned()),
+    ];
+    let backend = test_backend(&args, env);
+    let vars = TemplateVars::new(&args, "localhost", "/srv/app", None);
+
+    let scripts = [format!("allow-failure: {} {{{{release_dir}}}} 'two words'", script.display())];
+    let mut stats = TransferStats::default();
+    run_scripts(&backend, "post-transfer", &scripts, &vars, &mut stats).unwrap();
+    assert_eq!(stats.commands[0].1.status, 3);
+    assert_eq!(fs::read_to_string(&output).unwrap(), "/srv/app two words prod\n");
+    // The private directory of every copy is gone, whether the script failed or not
+    let leftovers = || {
+        let prefix = format!("ci-transfer-{}-", std::process::id());
+        fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .count()
+    };
+    assert_eq!(leftovers(), 0);
+
+    let scripts = [script.to_string_lossy().into_owned()];
+    assert!(run_scripts(&backend, "post-transfer", &scripts, &vars, &mut stats).is_err());
+    // The failed script is still reported, under the path it was given as
+    assert_eq!(stats.commands.len(), 2);
+    assert_eq!(stats.commands[1].1.command, scripts[0]);
+    assert_eq!(stats.commands[1].1.status, 3);
+    assert_eq!(leftovers(), 0);
+
+    fs::remove_dir_all(&root).unwrap();
+}
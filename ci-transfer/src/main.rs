@@ -1,29 +1,85 @@
+#[macro_use]
+mod mask;
+mod archive;
+mod backend;
+mod command;
+mod dry_run;
 mod error;
+mod fanout;
+mod filter;
+mod health;
+mod jump;
+mod local;
+mod manifest;
+mod object_store;
 mod oss;
+mod release;
+mod report;
+mod s3;
 mod ssh;
+mod template;
 
-use clap::Parser;
+use archive::Compression;
+use backend::TransferStats;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use command::Shell;
+use dry_run::{print_plan, PlanFormat};
 use error::TransferError;
+use fanout::{deploy_hosts, print_summary, HostOutcome};
+use health::HealthFrom;
+use std::time::Instant;
+use local::handle_local;
+use manifest::Manifest;
+use mask::register_secrets;
+use object_store::Sidecar;
 use oss::{handle_oss, parse_destination_oss};
-use ssh::{handle_ssh, parse_destination_ssh};
+use release::default_release_name;
+use report::{publish, Report, ReportFormat};
+use s3::{handle_s3, parse_destination_s3};
+use ssh::parse_destination_ssh;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// Deploy manifest (TOML, or YAML with .yaml/.yml extension) providing defaults for the flags below
+    #[clap(long, env = "CI_TRANSFER_MANIFEST")]
+    manifest: Option<String>,
+
     /// Source file or directory paths (can be empty for side-effect only operations)
     #[clap(short, long, multiple_values = true)]
     source: Vec<String>,
 
-    /// Destination in format user:pass@ip:/path
-    /// Or base64 encoded destination
+    /// Destination in format user:pass@ip:/path (password optional: user@ip:/path)
+    /// Or base64 encoded destination, repeat to deploy to several hosts
     #[clap(short, long)]
-    destination: Option<String>,
+    destination: Vec<String>,
 
     /// Transfer files to aliyun OSS
     /// base64 encoded Configuration
     #[clap(short, long)]
     oss_destination: Option<String>,
 
+    /// Copy files into a local directory, e.g. an NFS mount, and run commands on this machine
+    #[clap(long)]
+    local_destination: Option<String>,
+
+    /// Transfer files to an S3 compatible bucket (AWS S3, MinIO)
+    /// JSON or base64 encoded JSON configuration
+    #[clap(long)]
+    s3_destination: Option<String>,
+
+    /// Only transfer files in source directories matching these gitignore-style patterns
+    #[clap(long)]
+    include: Vec<String>,
+
+    /// Skip files and directories in source directories matching these gitignore-style patterns
+    #[clap(long)]
+    exclude: Vec<String>,
+
+    /// File with exclude patterns, one per line (default: .transferignore if it exists)
+    #[clap(long)]
+    ignore_file: Option<String>,
+
     /// SSH commands to execute before transfer
     /// Or base64 encoded commands
     #[clap(long, multiple_values = true)]
@@ -34,23 +90,319 @@ struct Args {
     #[clap(short, long, multiple_values = true)]
     commands: Vec<String>,
 
+    /// Environment variable exported to every command, as KEY=VAL
+    #[clap(long)]
+    env: Vec<String>,
+
+    /// Export the local environment variables matching this pattern (`*` wildcard) to every command
+    #[clap(long)]
+    forward_env: Vec<String>,
+
+    /// Shell running every command: bash, sh, or none to hand commands to the login shell as they are
+    #[clap(long, arg_enum, default_value = "bash")]
+    shell: Shell,
+
+    /// Local script uploaded to the host and run after --precommands, as "path [arguments]"
+    #[clap(long)]
+    prescript: Vec<String>,
+
+    /// Local script uploaded to the host and run after --commands, as "path [arguments]"
+    #[clap(long)]
+    script: Vec<String>,
+
+    /// URL polled after the commands finished, the deploy fails and rolls back unless it becomes healthy
+    #[clap(long)]
+    health_url: Option<String>,
+
+    /// HTTP status the --health-url must answer (default: any 2xx)
+    #[clap(long, requires = "health-url")]
+    health_status: Option<u16>,
+
+    /// Regex the --health-url response body must match
+    #[clap(long, requires = "health-url")]
+    health_body: Option<String>,
+
+    /// Seconds between --health-url requests
+    #[clap(long, default_value = "2")]
+    health_interval: u64,
+
+    /// Seconds --health-url may take to become healthy
+    #[clap(long, default_value = "60")]
+    health_timeout: u64,
+
+    /// Request --health-url from this machine (runner) or from the SSH host with curl (host)
+    #[clap(long, arg_enum, default_value = "runner")]
+    health_from: HealthFrom,
+
+    /// Commands run when the health check fails, after switching back to the previous release
+    /// Or base64 encoded commands
+    #[clap(long, multiple_values = true)]
+    rollback_commands: Vec<String>,
+
+    /// Timeout in seconds for each remote command (override per command with `timeout=<secs>:`)
+    #[clap(long)]
+    command_timeout: Option<u64>,
+
+    /// Timeout in seconds for all remote commands of a run together
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Verify uploads: SHA-256 against the remote file for SSH, MD5 against the ETag for OSS
+    #[clap(long)]
+    verify: bool,
+
+    /// Turn off --verify enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "verify")]
+    no_verify: bool,
+
+    /// Send each source directory as one compressed tarball extracted by `tar -x` remotely (SSH only)
+    #[clap(long, conflicts_with_all = &["sync", "resumable", "verify"])]
+    archive: bool,
+
+    /// Turn off --archive enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "archive")]
+    no_archive: bool,
+
+    /// Compression of --archive tarballs, zstd needs the zstd binary on the remote host
+    #[clap(long, arg_enum, default_value = "gzip")]
+    compression: Compression,
+
+    /// Upload files over SFTP via a temporary .part file that is resumed on retry
+    #[clap(long)]
+    resumable: bool,
+
+    /// Turn off --resumable enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "resumable")]
+    no_resumable: bool,
+
+    /// Reconnect and retry this many times when the SSH connection or transfer fails
+    #[clap(long, default_value = "0")]
+    retries: u32,
+
+    /// Initial delay in seconds between retries, doubled after each attempt
+    #[clap(long, default_value = "2")]
+    retry_delay: u64,
+
+    /// Number of SSH destinations deployed (or OSS objects uploaded) concurrently
+    #[clap(long, default_value = "4")]
+    parallel: usize,
+
+    /// Also upload `<key>.md5` or `<key>.sha256` with the hex digest of every uploaded OSS object
+    #[clap(long, arg_enum)]
+    sidecar: Vec<Sidecar>,
+
+    /// OSS files of at least this many MiB are uploaded in parts
+    #[clap(long, default_value = "64")]
+    multipart_threshold: u64,
+
+    /// Part size in MiB for OSS multipart uploads
+    #[clap(long, default_value = "8")]
+    part_size: u64,
+
+    /// Rolling deploy in batches of this many hosts, stopping after the first failed batch
+    #[clap(long)]
+    rolling: Option<usize>,
+
     /// SSH port (default: 22)
     #[clap(long, default_value = "22")]
     port: u16,
+
+    /// Jump host (bastion) as user[:pass]@host[:port][?key=/path] or base64 encoded, repeat for several
+    /// hops in order. `key=` is the hop's own private key, tried before the shared key and agent options
+    #[clap(long)]
+    jump_host: Vec<String>,
+
+    /// SSH private key file used for authentication
+    #[clap(long, env = "CI_TRANSFER_KEY_FILE")]
+    key_file: Option<String>,
+
+    /// SSH private key content (PEM or base64 encoded PEM)
+    #[clap(long, env = "CI_TRANSFER_KEY", hide_env_values = true)]
+    key: Option<String>,
+
+    /// Passphrase of the SSH private key
+    #[clap(long, env = "CI_TRANSFER_KEY_PASSPHRASE", hide_env_values = true)]
+    key_passphrase: Option<String>,
+
+    /// Environment variables whose values are secrets to redact from all output
+    #[clap(long)]
+    secret_env: Vec<String>,
+
+    /// Authenticate with the running ssh-agent
+    #[clap(long)]
+    ssh_agent: bool,
+
+    /// Turn off --ssh-agent enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "ssh-agent")]
+    no_ssh_agent: bool,
+
+    /// known_hosts file used to verify the server host key (default: ~/.ssh/known_hosts)
+    #[clap(long, env = "CI_TRANSFER_KNOWN_HOSTS")]
+    known_hosts: Option<String>,
+
+    /// Expected SHA256 fingerprint of the server host key, e.g. SHA256:nThbg6kX...
+    #[clap(long, env = "CI_TRANSFER_HOST_KEY_FINGERPRINT")]
+    host_key_fingerprint: Option<String>,
+
+    /// Accept host keys not found in known_hosts (and record them if --known-hosts is given)
+    #[clap(long)]
+    trust_on_first_use: bool,
+
+    /// Turn off --trust-on-first-use enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "trust-on-first-use")]
+    no_trust_on_first_use: bool,
+
+    /// Only upload files whose size or mtime differ from the remote copy (SSH, local).
+    /// OSS/S3 compare size and MD5 with the object ETag and ignore --checksum and --delete
+    #[clap(long)]
+    sync: bool,
+
+    /// Turn off --sync enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "sync")]
+    no_sync: bool,
+
+    /// With --sync, compare SHA-256 checksums instead of mtime
+    #[clap(long, requires = "sync")]
+    checksum: bool,
+
+    /// Turn off --checksum enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "checksum")]
+    no_checksum: bool,
+
+    /// With --sync, delete remote files which no longer exist in a source directory
+    #[clap(long, requires = "sync")]
+    delete: bool,
+
+    /// Turn off --delete enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "delete")]
+    no_delete: bool,
+
+    /// Upload into <path>/releases/<name> and switch <path>/current to it after commands succeed
+    #[clap(long)]
+    release: bool,
+
+    /// Turn off --release enabled by the manifest
+    #[clap(long, hide = true, overrides_with = "release")]
+    no_release: bool,
+
+    /// Release directory name (default: UTC timestamp), e.g. the commit SHA
+    #[clap(long, requires = "release")]
+    release_name: Option<String>,
+
+    /// Number of releases to keep on the remote host
+    #[clap(long, default_value = "5")]
+    keep_releases: usize,
+
+    /// Print the files and commands of every destination without connecting anywhere
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Output format of --dry-run
+    #[clap(long, arg_enum, default_value = "text")]
+    plan_format: PlanFormat,
+
+    /// Print a report of files, bytes, durations and command exit statuses at the end
+    #[clap(long, arg_enum)]
+    report: Option<ReportFormat>,
+
+    /// Write the --report to this file instead of stdout
+    #[clap(long, requires = "report")]
+    report_file: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<(), TransferError> {
-    let args = Args::parse();
-    let mut transfer_done = false;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Point <path>/current back to the previous release, then run --commands
+    Rollback {
+        /// Release name to roll back to instead of the previous one
+        #[clap(long)]
+        to: Option<String>,
+    },
+}
+
+fn main() {
+    // Errors are printed through the masking eprintln!, not as main's return value, and with
+    // Display: Debug would escape quotes and newlines inside a secret so it no longer matches
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), TransferError> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     let mut errors: Vec<String> = Vec::new();
+    let mut report = Report::new();
+    // The report is published for every failure, also one that stopped the run early
+    let result = deploy(&mut args, &matches, &mut report, &mut errors);
+    if let Err(e) = &result {
+        errors.push(e.to_string());
+    }
+    if !args.dry_run {
+        report.finish(&errors);
+        publish(&args, &report)?;
+    }
+    result?;
+
+    if !errors.is_empty() {
+        return Err(TransferError::Other(errors.join("\n")));
+    }
+
+    Ok(())
+}
+
+/// Load the manifest and deploy to every destination, adding the results to `report` and the
+/// failures of single destinations to `errors`
+fn deploy(
+    args: &mut Args,
+    matches: &ArgMatches,
+    report: &mut Report,
+    errors: &mut Vec<String>,
+) -> Result<(), TransferError> {
+    if let Some(path) = args.manifest.clone() {
+        Manifest::load(&path)?.apply(args, matches)?;
+    }
+    register_secrets(args);
+    // Clap only sees the command line, the manifest may have enabled either side
+    if args.archive && (args.sync || args.resumable || args.verify) {
+        return Err(TransferError::Other(
+            "--archive cannot be used with --sync, --resumable or --verify".into(),
+        ));
+    }
+    // Releases are directories and symlinks on an SSH host, local and object store destinations have none
+    if args.destination.is_empty() {
+        if args.command.is_some() {
+            return Err(TransferError::Other("rollback needs an SSH destination (-d)".into()));
+        }
+        if args.release {
+            return Err(TransferError::Other("--release needs an SSH destination (-d)".into()));
+        }
+    }
+    // Every host of a fan-out deploy must use the same release name
+    if args.release && args.release_name.is_none() {
+        args.release_name = Some(default_release_name());
+    }
+    if args.dry_run {
+        return print_plan(args);
+    }
+    let args = &*args;
+    let mut transfer_done = false;
 
     if let Some(oss_dest) = &args.oss_destination {
         transfer_done = true;
         match parse_destination_oss(oss_dest) {
             Ok(oss_config) => {
-                if let Err(e) = handle_oss(&args.source, oss_config) {
+                let (target, started) = (oss_config.target(), Instant::now());
+                let mut stats = TransferStats::default();
+                let result = handle_oss(args, oss_config, &mut stats);
+                if let Err(e) = &result {
                     errors.push(format!("OSS transfer failed: {}", e));
                 }
+                report.add("oss", target, &HostOutcome::new(result, stats), started.elapsed());
             }
             Err(_) => {
                 errors.push("Invalid oss_destination format".to_string());
@@ -58,26 +410,72 @@ fn main() -> Result<(), TransferError> {
         }
     }
 
-    if let Some(destination) = &args.destination {
+    if let Some(s3_dest) = &args.s3_destination {
         transfer_done = true;
-        match parse_destination_ssh(destination) {
-            Ok(ssh_config) => {
-                if let Err(e) = handle_ssh(&args, ssh_config) {
-                    errors.push(format!("SSH transfer failed: {}", e));
+        match parse_destination_s3(s3_dest) {
+            Ok(s3_config) => {
+                let (target, started) = (s3_config.target(), Instant::now());
+                let mut stats = TransferStats::default();
+                let result = handle_s3(args, s3_config, &mut stats);
+                if let Err(e) = &result {
+                    errors.push(format!("S3 transfer failed: {}", e));
                 }
+                report.add("s3", target, &HostOutcome::new(result, stats), started.elapsed());
             }
             Err(_) => {
-                errors.push("Invalid destination format".to_string());
+                errors.push("Invalid s3_destination format".to_string());
             }
         }
     }
 
+    if let Some(local_dest) = &args.local_destination {
+        transfer_done = true;
+        let started = Instant::now();
+        let mut stats = TransferStats::default();
+        let result = handle_local(args, local_dest, &mut stats);
+        if let Err(e) = &result {
+            errors.push(format!("Local transfer failed: {}", e));
+        }
+        report.add("local", local_dest.clone(), &HostOutcome::new(result, stats), started.elapsed());
+    }
+
+    if !args.destination.is_empty() {
+        transfer_done = true;
+        let ssh_configs: Result<Vec<_>, _> = args
+            .destination
+            .iter()
+            .map(|destination| parse_destination_ssh(destination))
+            .collect();
+        if let Ok(ssh_configs) = ssh_configs {
+            let results = deploy_hosts(args, ssh_configs);
+            if results.len() > 1 {
+                print_summary(&results);
+            }
+            report.add_hosts(&results);
+            for result in results {
+                match result.outcome {
+                    HostOutcome::Failed(e, _) if args.destination.len() > 1 => {
+                        errors.push(format!("SSH transfer to {} failed: {}", result.host, e))
+                    }
+                    HostOutcome::Failed(e, _) => errors.push(format!("SSH transfer failed: {}", e)),
+                    HostOutcome::Skipped => errors.push(format!(
+                        "SSH transfer to {} skipped after a failed rolling batch",
+                        result.host
+                    )),
+                    HostOutcome::Success(_) => {}
+                }
+            }
+        } else {
+            errors.push("Invalid destination format".to_string());
+        }
+    }
+
     // Check if there are any commands to execute or transfers to do
-    let has_precommands = !args.precommands.is_empty();
-    let has_commands = !args.commands.is_empty();
+    let has_precommands = !args.precommands.is_empty() || !args.prescript.is_empty();
+    let has_commands = !args.commands.is_empty() || !args.script.is_empty();
     let has_sources = !args.source.is_empty();
     
-    if !transfer_done && !(has_precommands || has_commands) {
+    if !(transfer_done || has_precommands || has_commands) {
         let json_str = r#"
         {
             "oss_bucket": "my-bucket",
@@ -94,8 +492,7 @@ fn main() -> Result<(), TransferError> {
             you can put user:pass@ip:/path to use ssh destination, 
             or put json format like {json_str} to use aliyun oss destination
             or use base64 encode ssh/oss format"
-            )
-            .into(),
+            ),
         ));
     }
     
@@ -104,9 +501,5 @@ fn main() -> Result<(), TransferError> {
         println!("Warning: No destination specified, but commands found. Commands will not be executed without a destination.");
     }
 
-    if !errors.is_empty() {
-        return Err(TransferError::Other(errors.join("\n").into()));
-    }
-
     Ok(())
 }
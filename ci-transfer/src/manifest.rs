@@ -0,0 +1,315 @@
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::archive::Compression;
+use crate::command::Shell;
+use crate::error::TransferError;
+use crate::health::HealthFrom;
+use crate::object_store::Sidecar;
+use crate::oss::OssConfig;
+use crate::s3::S3Config;
+use crate::Args;
+
+/// OSS destination in a manifest: either the usual JSON/base64 string or an inline table
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OssDestination {
+    Encoded(String),
+    Config(OssConfig),
+}
+
+/// S3 destination in a manifest, like [`OssDestination`]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum S3Destination {
+    Encoded(String),
+    Config(S3Config),
+}
+
+/// SSH destinations in a manifest: a single string or a list
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Destinations {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Deploy manifest (`--manifest deploy.toml` or `.yaml`), mirroring the command line flags.
+/// Flags given on the command line or via env take precedence over manifest values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    source: Vec<String>,
+    destination: Option<Destinations>,
+    oss_destination: Option<OssDestination>,
+    s3_destination: Option<S3Destination>,
+    local_destination: Option<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    ignore_file: Option<String>,
+    precommands: Vec<String>,
+    commands: Vec<String>,
+    prescript: Vec<String>,
+    script: Vec<String>,
+    health_url: Option<String>,
+    health_status: Option<u16>,
+    health_body: Option<String>,
+    health_interval: Option<u64>,
+    health_timeout: Option<u64>,
+    health_from: Option<HealthFrom>,
+    rollback_commands: Vec<String>,
+    env: Vec<String>,
+    forward_env: Vec<String>,
+    command_timeout: Option<u64>,
+    timeout: Option<u64>,
+    port: Option<u16>,
+    jump_host: Vec<String>,
+    key_file: Option<String>,
+    secret_env: Vec<String>,
+    ssh_agent: Option<bool>,
+    known_hosts: Option<String>,
+    host_key_fingerprint: Option<String>,
+    trust_on_first_use: Option<bool>,
+    sync: Option<bool>,
+    checksum: Option<bool>,
+    delete: Option<bool>,
+    release: Option<bool>,
+    release_name: Option<String>,
+    keep_releases: Option<usize>,
+    parallel: Option<usize>,
+    sidecar: Vec<Sidecar>,
+    multipart_threshold: Option<u64>,
+    part_size: Option<u64>,
+    rolling: Option<usize>,
+    verify: Option<bool>,
+    archive: Option<bool>,
+    compression: Option<Compression>,
+    shell: Option<Shell>,
+    resumable: Option<bool>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+}
+
+impl Manifest {
+    /// Parse a manifest, choosing YAML for `.yaml`/`.yml` files and TOML otherwise
+    pub fn load(path: &str) -> Result<Manifest, TransferError> {
+        let content = std::fs::read_to_string(path)?;
+        let is_yaml = matches!(
+            Path::new(path).extension().and_then(|ext| ext.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        let manifest = if is_yaml {
+            serde_yaml::from_str(&content).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&content).map_err(|e| e.to_string())
+        };
+        manifest.map_err(|e| TransferError::ManifestError(format!("{}: {}", path, e)))
+    }
+
+    /// Fill `args` with manifest values wherever the flag was not given explicitly
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<(), TransferError> {
+        let explicit = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+            )
+        };
+        // Boolean flags are switched off on the command line with their hidden --no-<flag>
+        let flag = |value: &mut bool, manifest: Option<bool>, id: &str| {
+            if let Some(manifest) = manifest.filter(|_| !explicit(id) && !explicit(&format!("no-{}", id))) {
+                *value = manifest;
+            }
+        };
+
+        if args.source.is_empty() {
+            args.source = self.source;
+        }
+        if args.destination.is_empty() {
+            args.destination = match self.destination {
+                Some(Destinations::One(destination)) => vec![destination],
+                Some(Destinations::Many(destinations)) => destinations,
+                None => Vec::new(),
+            };
+        }
+        if args.oss_destination.is_none() {
+            args.oss_destination = match self.oss_destination {
+                Some(OssDestination::Encoded(encoded)) => Some(encoded),
+                Some(OssDestination::Config(config)) => {
+                    Some(serde_json::to_string(&config).map_err(TransferError::JsonParseError)?)
+                }
+                None => None,
+            };
+        }
+        if args.s3_destination.is_none() {
+            args.s3_destination = match self.s3_destination {
+                Some(S3Destination::Encoded(encoded)) => Some(encoded),
+                Some(S3Destination::Config(config)) => {
+                    Some(serde_json::to_string(&config).map_err(TransferError::JsonParseError)?)
+                }
+                None => None,
+            };
+        }
+        args.local_destination = args.local_destination.take().or(self.local_destination);
+        if args.include.is_empty() {
+            args.include = self.include;
+        }
+        if args.exclude.is_empty() {
+            args.exclude = self.exclude;
+        }
+        args.ignore_file = args.ignore_file.take().or(self.ignore_file);
+        if args.prescript.is_empty() {
+            args.prescript = self.prescript;
+        }
+        if args.script.is_empty() {
+            args.script = self.script;
+        }
+        args.health_url = args.health_url.take().or(self.health_url);
+        args.health_status = args.health_status.or(self.health_status);
+        args.health_body = args.health_body.take().or(self.health_body);
+        if let Some(interval) = self.health_interval.filter(|_| !explicit("health-interval")) {
+            args.health_interval = interval;
+        }
+        if let Some(timeout) = self.health_timeout.filter(|_| !explicit("health-timeout")) {
+            args.health_timeout = timeout;
+        }
+        if let Some(health_from) = self.health_from.filter(|_| !explicit("health-from")) {
+            args.health_from = health_from;
+        }
+        if args.rollback_commands.is_empty() {
+            args.rollback_commands = self.rollback_commands;
+        }
+        if args.env.is_empty() {
+            args.env = self.env;
+        }
+        if args.forward_env.is_empty() {
+            args.forward_env = self.forward_env;
+        }
+        if args.jump_host.is_empty() {
+            args.jump_host = self.jump_host;
+        }
+        if args.secret_env.is_empty() {
+            args.secret_env = self.secret_env;
+        }
+        if args.precommands.is_empty() {
+            args.precommands = self.precommands;
+        }
+        if args.commands.is_empty() {
+            args.commands = self.commands;
+        }
+        args.command_timeout = args.command_timeout.or(self.command_timeout);
+        args.timeout = args.timeout.or(self.timeout);
+        if let Some(port) = self.port.filter(|_| !explicit("port")) {
+            args.port = port;
+        }
+        args.key_file = args.key_file.take().or(self.key_file);
+        flag(&mut args.ssh_agent, self.ssh_agent, "ssh-agent");
+        args.known_hosts = args.known_hosts.take().or(self.known_hosts);
+        args.host_key_fingerprint = args.host_key_fingerprint.take().or(self.host_key_fingerprint);
+        flag(&mut args.trust_on_first_use, self.trust_on_first_use, "trust-on-first-use");
+        flag(&mut args.sync, self.sync, "sync");
+        flag(&mut args.checksum, self.checksum, "checksum");
+        flag(&mut args.delete, self.delete, "delete");
+        flag(&mut args.release, self.release, "release");
+        args.release_name = args.release_name.take().or(self.release_name);
+        if let Some(keep_releases) = self.keep_releases.filter(|_| !explicit("keep-releases")) {
+            args.keep_releases = keep_releases;
+        }
+        if args.sidecar.is_empty() {
+            args.sidecar = self.sidecar;
+        }
+        if let Some(threshold) = self.multipart_threshold.filter(|_| !explicit("multipart-threshold")) {
+            args.multipart_threshold = threshold;
+        }
+        if let Some(part_size) = self.part_size.filter(|_| !explicit("part-size")) {
+            args.part_size = part_size;
+        }
+        if let Some(parallel) = self.parallel.filter(|_| !explicit("parallel")) {
+            args.parallel = parallel;
+        }
+        args.rolling = args.rolling.or(self.rolling);
+        flag(&mut args.verify, self.verify, "verify");
+        flag(&mut args.archive, self.archive, "archive");
+        if let Some(compression) = self.compression.filter(|_| !explicit("compression")) {
+            args.compression = compression;
+        }
+        if let Some(shell) = self.shell.filter(|_| !explicit("shell")) {
+            args.shell = shell;
+        }
+        flag(&mut args.resumable, self.resumable, "resumable");
+        if let Some(retries) = self.retries.filter(|_| !explicit("retries")) {
+            args.retries = retries;
+        }
+        if let Some(retry_delay) = self.retry_delay.filter(|_| !explicit("retry-delay")) {
+            args.retry_delay = retry_delay;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn parse_args(argv: &[&str]) -> (Args, ArgMatches) {
+    use clap::{CommandFactory, FromArgMatches};
+    let matches = Args::command().get_matches_from(argv);
+    (Args::from_arg_matches(&matches).unwrap(), matches)
+}
+
+#[test]
+fn test_manifest_toml_cli_override() {
+    let manifest: Manifest = toml::from_str(
+        r#"
+        source = ["dist/"]
+        destination = "deploy@10.0.0.1:/srv/app"
+        commands = ["systemctl restart app"]
+        port = 2222
+        keep_releases = 3
+
+        [oss_destination]
+        oss_bucket = "bucket"
+        oss_endpoint = "oss-cn-beijing.aliyuncs.com"
+        key_id = "id"
+        key_secret = "secret"
+        path = "/static/"
+        "#,
+    )
+    .unwrap();
+    let (mut args, matches) = parse_args(&["ci-transfer", "--port", "22", "-c", "echo override"]);
+    manifest.apply(&mut args, &matches).unwrap();
+
+    assert_eq!(args.source, vec!["dist/"]);
+    assert_eq!(args.destination, vec!["deploy@10.0.0.1:/srv/app"]);
+    assert_eq!(args.commands, vec!["echo override"]);
+    assert_eq!(args.port, 22);
+    assert_eq!(args.keep_releases, 3);
+    assert!(args.oss_destination.unwrap().contains("\"oss_bucket\":\"bucket\""));
+}
+
+#[test]
+fn test_manifest_yaml() {
+    let manifest: Manifest = serde_yaml::from_str(
+        "source: [app.jar]\ndestination: [a@10.0.0.1:/srv, b@10.0.0.2:/srv]\nprecommands:\n  - systemctl stop app\nsync: true\n",
+    )
+    .unwrap();
+    let (mut args, matches) = parse_args(&["ci-transfer"]);
+    manifest.apply(&mut args, &matches).unwrap();
+
+    assert_eq!(args.source, vec!["app.jar"]);
+    assert_eq!(args.destination.len(), 2);
+    assert_eq!(args.precommands, vec!["systemctl stop app"]);
+    assert!(args.sync);
+    assert_eq!(args.port, 22);
+}
+
+#[test]
+fn test_manifest_flags_off() {
+    let manifest: Manifest = toml::from_str("sync = true
+delete = true
+verify = false
+").unwrap();
+    let (mut args, matches) = parse_args(&["ci-transfer", "--no-sync", "--verify"]);
+    manifest.apply(&mut args, &matches).unwrap();
+
+    assert!(!args.sync);
+    assert!(args.delete);
+    assert!(args.verify);
+}
@@ -0,0 +1,117 @@
+use std::sync::RwLock;
+
+use crate::jump::parse_jump_host;
+use crate::oss::parse_destination_oss;
+use crate::report::github_actions;
+use crate::s3::parse_destination_s3;
+use crate::ssh::{decode_inline_key, parse_destination_ssh};
+use crate::Args;
+
+/// Shadow `println!` and friends so every line ci-transfer prints goes through `mask`.
+/// `#[macro_use] mod mask;` comes first in main.rs, which makes these visible to every module.
+macro_rules! println {
+    () => {
+        std::println!()
+    };
+    ($($arg:tt)*) => {
+        std::println!("{}", $crate::mask::mask(&format!($($arg)*)))
+    };
+}
+
+macro_rules! print {
+    ($($arg:tt)*) => {
+        std::print!("{}", $crate::mask::mask(&format!($($arg)*)))
+    };
+}
+
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        std::eprintln!("{}", $crate::mask::mask(&format!($($arg)*)))
+    };
+}
+
+/// Replaces secrets in output
+const MASK: &str = "***";
+
+/// Secrets shorter than this would mask ordinary words and are left alone
+const MIN_SECRET_LEN: usize = 3;
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Redact `secret` from all further output, and from the GitHub Actions log with `::add-mask::`.
+/// A multi-line secret is also registered line by line.
+pub fn add_secret(secret: &str) {
+    let lines = secret.lines().map(str::trim).filter(|line| *line != secret);
+    for secret in std::iter::once(secret).chain(lines) {
+        if secret.len() < MIN_SECRET_LEN {
+            continue;
+        }
+        let mut secrets = SECRETS.write().unwrap();
+        if secrets.iter().any(|known| known == secret) {
+            continue;
+        }
+        if github_actions() && !secret.contains('\n') {
+            std::println!("::add-mask::{}", secret);
+        }
+        secrets.push(secret.to_string());
+        // Longest first, so a secret containing another one is replaced as a whole
+        secrets.sort_by_key(|known| std::cmp::Reverse(known.len()));
+    }
+}
+
+/// `text` with every registered secret replaced by `***`
+pub fn mask(text: &str) -> String {
+    let secrets = SECRETS.read().unwrap();
+    let mut masked = text.to_string();
+    for secret in secrets.iter() {
+        if masked.contains(secret.as_str()) {
+            masked = masked.replace(secret.as_str(), MASK);
+        }
+    }
+    masked
+}
+
+/// Register every secret given on the command line or in the manifest before anything runs:
+/// destination and jump host passwords, OSS/S3 keys, SSH key material and the `--secret-env` variables
+pub fn register_secrets(args: &Args) {
+    // Parsing registers passwords, key secrets and base64 encoded destinations
+    for destination in &args.destination {
+        let _ = parse_destination_ssh(destination);
+    }
+    for jump_host in &args.jump_host {
+        let _ = parse_jump_host(jump_host);
+    }
+    if let Some(oss_dest) = &args.oss_destination {
+        let _ = parse_destination_oss(oss_dest);
+    }
+    if let Some(s3_dest) = &args.s3_destination {
+        let _ = parse_destination_s3(s3_dest);
+    }
+    if let Some(key) = &args.key {
+        add_secret(key);
+        if let Ok(pem) = decode_inline_key(key) {
+            add_secret(&pem);
+        }
+    }
+    if let Some(passphrase) = &args.key_passphrase {
+        add_secret(passphrase);
+    }
+    for name in &args.secret_env {
+        if let Ok(value) = std::env::var(name) {
+            add_secret(&value);
+        }
+    }
+}
+
+#[test]
+fn test_mask() {
+    add_secret("hunter2-secret");
+    add_secret("hunter2-secret-long");
+    add_secret("ab");
+    add_secret("line-one\nline-two");
+    assert_eq!(
+        mask("pass=hunter2-secret-long, short=hunter2-secret, ab"),
+        "pass=***, short=***, ab"
+    );
+    assert_eq!(mask("key: line-two"), "key: ***");
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+
+use clap::ArgEnum;
+use md5::{Digest, Md5};
+use serde::Deserialize;
+
+use crate::error::TransferError;
+use crate::ssh::local_sha256;
+
+/// Digest object written next to every uploaded object by --sidecar
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sidecar {
+    Md5,
+    Sha256,
+}
+
+impl Sidecar {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Sidecar::Md5 => "md5",
+            Sidecar::Sha256 => "sha256",
+        }
+    }
+
+    /// Key of the sidecar object and its content, the lowercase hex digest of `local_path`
+    pub fn object(&self, key: &str, local_path: &Path) -> Result<(String, String), TransferError> {
+        let digest = match self {
+            Sidecar::Md5 => local_md5(local_path)?,
+            Sidecar::Sha256 => local_sha256(local_path)?,
+        };
+        Ok((format!("{}.{}", key, self.extension()), digest))
+    }
+}
+
+/// Object key for a remote path: absolute, like the OSS SDK's format_key
+pub fn object_key(remote: &str) -> String {
+    let key = remote.replace('\\', "/");
+    if key.starts_with('/') {
+        key
+    } else {
+        format!("/{}", key)
+    }
+}
+
+/// Percent-encode everything except RFC 3986 unreserved characters and `/`, for keys in URLs
+pub fn uri_encode(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Hex encoded MD5 of a local file, the ETag assigned to objects uploaded with a single PUT
+pub fn local_md5(path: &Path) -> Result<String, TransferError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Md5::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -1,11 +1,23 @@
+mod multipart;
+
 use std::{
     fs::{self},
+    io::Read,
     path::Path,
 };
 
+use crate::backend::{transfer, Backend, DirLayout, FileJob, Progress, TransferStats};
+use crate::command::CommandResult;
 use crate::error::TransferError;
+use crate::mask::add_secret;
+use crate::object_store::{local_md5, object_key};
+use crate::Args;
+use aliyun_oss_rust_sdk::metadata::ObjectMetadata;
 use aliyun_oss_rust_sdk::oss::OSS;
+use aliyun_oss_rust_sdk::request::RequestBuilder;
 use base64::{engine::general_purpose, Engine as _};
+use crc::{Crc, CRC_64_XZ};
+use multipart::MultipartUploader;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -19,6 +31,16 @@ pub struct OssConfig {
     override_existing: Option<bool>,
 }
 
+impl OssConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn target(&self) -> String {
+        format!("oss://{}/{}", self.oss_bucket, self.path.trim_start_matches('/'))
+    }
+}
+
 impl From<OssConfig> for OSS {
     fn from(value: OssConfig) -> Self {
         OSS::new(
@@ -34,98 +56,213 @@ pub fn parse_destination_oss(destination: &str) -> Result<OssConfig, TransferErr
     if destination.is_empty() {
         return Err(TransferError::Other("Destination cannot be empty".into()));
     }
-    match general_purpose::STANDARD.decode(&destination) {
-        Ok(decoded) => match std::str::from_utf8(&decoded) {
-            Ok(s) => return parse_destination_oss(s),
-            _ => (),
-        },
-        _ => (),
+    if let Ok(decoded) = general_purpose::STANDARD.decode(destination) {
+        if let Ok(s) = std::str::from_utf8(&decoded) {
+            add_secret(destination);
+            return parse_destination_oss(s);
+        }
     }
     let config: OssConfig =
-        serde_json::from_str(destination).map_err(|e| TransferError::JsonParseError(e))?;
+        serde_json::from_str(destination).map_err(TransferError::JsonParseError)?;
+    add_secret(&config.key_secret);
     Ok(config)
 }
 
-pub fn handle_oss(sources: &[String], oss_config: OssConfig) -> Result<(), TransferError> {
-    use aliyun_oss_rust_sdk::request::RequestBuilder;
+/// CRC-64/XZ of a local file, reported by OSS as x-oss-hash-crc64ecma for every object
+fn local_crc64(path: &Path) -> Result<String, TransferError> {
+    const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
+    let mut file = fs::File::open(path)?;
+    let mut digest = CRC64.digest();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buffer[..read]);
+    }
+    Ok(digest.finalize().to_string())
+}
 
-    let oss: OSS = oss_config.clone().into();
-    let build = RequestBuilder::new().with_expire(300);
-    
-    // Handle empty sources case (side-effect only)
-    if sources.is_empty() {
-        println!("No source files specified for OSS transfer - side-effect only operation");
-        return Ok(());
+/// Metadata of `key`, or None when the object does not exist
+fn head_object(
+    oss: &OSS,
+    build: &RequestBuilder,
+    key: &str,
+) -> Result<Option<ObjectMetadata>, TransferError> {
+    match oss.get_object_metadata(key, build.clone()) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.to_string().contains("404") => Ok(None),
+        Err(e) => Err(TransferError::OssError(format!("{}", e))),
     }
+}
 
-    for source in sources {
-        let source_path = Path::new(source);
+/// Digest algorithm plus the local and remote digests to compare: the MD5 ETag for objects
+/// uploaded with a single PUT, CRC-64 for multipart objects whose ETag is not an MD5
+fn object_digests(
+    metadata: &ObjectMetadata,
+    local_path: &Path,
+) -> Result<Option<(&'static str, String, String)>, TransferError> {
+    let etag = metadata
+        .etag()
+        .unwrap_or_default()
+        .trim_matches('"')
+        .to_lowercase();
+    if !etag.contains('-') {
+        return Ok(Some(("md5", local_md5(local_path)?, etag)));
+    }
+    match metadata.crc64() {
+        Some(crc64) => Ok(Some(("crc64", local_crc64(local_path)?, crc64))),
+        None => Ok(None),
+    }
+}
 
-        if !source_path.exists() {
-            return Err(TransferError::Other(format!(
-                "Source path {} does not exist",
-                source
-            )));
+/// Compare the uploaded object's digest with the local file
+fn verify_object(
+    oss: &OSS,
+    build: &RequestBuilder,
+    key: &str,
+    local_path: &Path,
+) -> Result<(), TransferError> {
+    let metadata = head_object(oss, build, key)?
+        .ok_or_else(|| TransferError::OssError(format!("{} not found after upload", key)))?;
+    match object_digests(&metadata, local_path)? {
+        Some((algorithm, expected, actual)) => {
+            if expected != actual {
+                return Err(TransferError::ChecksumMismatch {
+                    path: key.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+            println!("oss verify: {} {} {}", key, algorithm, expected);
         }
+        None => println!("oss verify: {} skipped, no digest for multipart object", key),
+    }
+    Ok(())
+}
 
-        if source_path.is_dir() {
-            let mut dirs_to_visit = vec![source_path.to_path_buf()];
-            while let Some(dir) = dirs_to_visit.pop() {
-                for entry in fs::read_dir(dir)? {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.is_dir() {
-                        dirs_to_visit.push(path);
-                    } else if path.is_file() {
-                        let relative_path = path.strip_prefix(source_path).unwrap();
-                        let oss_object_path = Path::new(&oss_config.path)
-                            .join(relative_path)
-                            .to_string_lossy()
-                            .into_owned();
-                        let real_path = oss_object_path.replace("\\", "/");
-                        
-                        println!("oss transfer: {}", real_path);
-
-                        oss.put_object_from_file(
-                            real_path,
-                            path.to_string_lossy().into_owned(),
-                            build.clone(),
-                        )
-                        .map_err(|e| TransferError::OssError(format!("{}", e)))?;
-                    }
-                }
-            }
-        } else if source_path.is_file() {
-            let oss_object_path = if oss_config.path.ends_with('/') {
-                let file_name = source_path.file_name().unwrap().to_str().unwrap();
-                format!("{}{}", oss_config.path, file_name)
-            } else {
-                // For multiple files, we need to create unique paths
-                if sources.len() > 1 {
-                    let file_name = source_path.file_name().unwrap().to_str().unwrap();
-                    format!("{}/{}", oss_config.path.trim_end_matches('/'), file_name)
-                } else {
-                    oss_config.path.clone()
-                }
-            };
-            let real_path = oss_object_path.replace("\\", "/");
-            println!("oss transfer: {}", real_path);
-            oss.put_object_from_file(real_path, source.to_string(), build.clone())
-                .map_err(|e| TransferError::OssError(format!("{}", e)))?;
+/// Why an existing object is left alone: `override_existing: false`, or --sync and unchanged
+fn skip_reason(
+    oss: &OSS,
+    build: &RequestBuilder,
+    oss_config: &OssConfig,
+    key: &str,
+    job: &FileJob,
+    args: &Args,
+) -> Result<Option<&'static str>, TransferError> {
+    let keep_existing = oss_config.override_existing == Some(false);
+    if !keep_existing && !args.sync {
+        return Ok(None);
+    }
+    let existing = head_object(oss, build, key)?;
+    skip_decision(keep_existing, args.sync, existing.as_ref(), job)
+}
+
+/// The decision of [`skip_reason`] for the object HEAD returned (None if there is none), only
+/// reading the local file: its size and digest must match the object for --sync to skip it
+fn skip_decision(
+    keep_existing: bool,
+    sync: bool,
+    existing: Option<&ObjectMetadata>,
+    job: &FileJob,
+) -> Result<Option<&'static str>, TransferError> {
+    let Some(metadata) = existing else {
+        return Ok(None);
+    };
+    if keep_existing {
+        return Ok(Some("exists"));
+    }
+    if !sync {
+        return Ok(None);
+    }
+    let unchanged = metadata.content_length() == Some(job.size.to_string())
+        && matches!(
+            object_digests(metadata, &job.local_path)?,
+            Some((_, local, remote)) if local == remote
+        );
+    Ok(unchanged.then_some("unchanged"))
+}
+
+/// Uploads into one OSS bucket
+struct OssBackend<'a> {
+    oss: OSS,
+    build: RequestBuilder,
+    config: &'a OssConfig,
+    multipart: MultipartUploader<'a>,
+    args: &'a Args,
+}
+
+impl Backend for OssBackend<'_> {
+    fn name(&self) -> &'static str {
+        "oss"
+    }
+
+    fn concurrency(&self) -> usize {
+        self.args.parallel
+    }
+
+    fn dir_layout(&self) -> DirLayout {
+        DirLayout::Contents
+    }
+
+    fn make_dir(&self, _remote_dir: &str) -> Result<(), TransferError> {
+        // Object storage has no directories, keys carry the full path
+        Ok(())
+    }
+
+    fn put_file(&self, job: &FileJob, progress: &Progress) -> Result<Option<&'static str>, TransferError> {
+        let (oss, build, args) = (&self.oss, &self.build, self.args);
+        let key = object_key(&job.remote);
+        if let Some(reason) = skip_reason(oss, build, self.config, &key, job, args)? {
+            return Ok(Some(reason));
+        }
+        if job.size >= args.multipart_threshold * 1024 * 1024 {
+            self.multipart.upload(&key, &job.local_path, progress)?;
         } else {
-            return Err(TransferError::Other(
-                format!("Path {} is neither a file nor directory", source).into(),
-            ));
+            oss.put_object_from_file(
+                key.clone(),
+                job.local_path.to_string_lossy().into_owned(),
+                build.clone(),
+            )
+            .map_err(|e| TransferError::OssError(format!("{}", e)))?;
+            progress.add_bytes(job.size);
+        }
+        if args.verify {
+            verify_object(oss, build, &key, &job.local_path)?;
         }
+        for sidecar in &args.sidecar {
+            let (key, digest) = sidecar.object(&key, &job.local_path)?;
+            oss.pub_object_from_buffer(key, digest.as_bytes(), build.clone())
+                .map_err(|e| TransferError::OssError(format!("{}", e)))?;
+        }
+        Ok(None)
     }
 
-    Ok(())
+    fn run_command(&self, command: &str) -> Result<CommandResult, TransferError> {
+        Err(TransferError::OssError(format!(
+            "cannot run `{}`, OSS destinations do not support commands",
+            command
+        )))
+    }
+}
+
+pub fn handle_oss(args: &Args, oss_config: OssConfig, stats: &mut TransferStats) -> Result<(), TransferError> {
+    let backend = OssBackend {
+        oss: oss_config.clone().into(),
+        build: RequestBuilder::new().with_expire(300),
+        config: &oss_config,
+        multipart: MultipartUploader::new(&oss_config, args.part_size * 1024 * 1024),
+        args,
+    };
+    transfer(&backend, args, &oss_config.path, stats)
 }
 
 #[test]
 fn test_handle_oss() {
+    use clap::Parser;
     let _ = handle_oss(
-        &["src".to_string()],
+        &Args::parse_from(["ci-transfer", "-s", "src"]),
         OssConfig {
             path: "/test".into(),
             oss_bucket: "test".into(),
@@ -134,5 +271,51 @@ fn test_handle_oss() {
             key_secret: "test".into(),
             override_existing: None,
         },
+        &mut TransferStats::default(),
     );
 }
+
+#[test]
+fn test_local_md5() {
+    let path = std::env::temp_dir().join("ci-transfer-test-md5");
+    fs::write(&path, "hello").unwrap();
+    assert_eq!(local_md5(&path).unwrap(), "5d41402abc4b2a76b9719d911017c592");
+    let (key, digest) = crate::object_store::Sidecar::Md5.object("/site/app.zip", &path).unwrap();
+    assert_eq!(key, "/site/app.zip.md5");
+    assert_eq!(digest, "5d41402abc4b2a76b9719d911017c592");
+    fs::write(&path, "123456789").unwrap();
+    assert_eq!(local_crc64(&path).unwrap(), "11051210869376104954");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_skip_decision() {
+    use reqwest::header::HeaderMap;
+    let path = std::env::temp_dir().join(format!("ci-transfer-test-skip-{}", std::process::id()));
+    fs::write(&path, "hello").unwrap();
+    let job = FileJob {
+        local_path: path.clone(),
+        remote: "/site/hello.txt".into(),
+        size: 5,
+    };
+    let object = |etag: &str, size: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert("etag", etag.parse().unwrap());
+        headers.insert("content-length", size.parse().unwrap());
+        ObjectMetadata::new(&headers)
+    };
+    let same = object("\"5D41402ABC4B2A76B9719D911017C592\"", "5");
+    let changed = object("\"00000000000000000000000000000000\"", "5");
+    let resized = object("\"5D41402ABC4B2A76B9719D911017C592\"", "6");
+    let multipart = object("\"5D41402ABC4B2A76B9719D911017C592-2\"", "5");
+
+    assert_eq!(skip_decision(true, false, None, &job).unwrap(), None);
+    assert_eq!(skip_decision(true, false, Some(&changed), &job).unwrap(), Some("exists"));
+    assert_eq!(skip_decision(false, false, Some(&same), &job).unwrap(), None);
+    assert_eq!(skip_decision(false, true, Some(&same), &job).unwrap(), Some("unchanged"));
+    assert_eq!(skip_decision(false, true, Some(&changed), &job).unwrap(), None);
+    assert_eq!(skip_decision(false, true, Some(&resized), &job).unwrap(), None);
+    // A multipart object without a CRC-64 header cannot be compared, so it is uploaded again
+    assert_eq!(skip_decision(false, true, Some(&multipart), &job).unwrap(), None);
+    fs::remove_file(&path).unwrap();
+}
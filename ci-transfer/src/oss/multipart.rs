@@ -0,0 +1,268 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use reqwest::blocking::{Client, Response};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use super::OssConfig;
+use crate::backend::Progress;
+use crate::error::TransferError;
+use crate::object_store::uri_encode;
+use crate::ssh::mtime_secs;
+
+/// Parts already uploaded for one multipart upload, saved after every part so that a
+/// later run can continue the same upload instead of starting over
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    upload_id: String,
+    size: u64,
+    mtime: u64,
+    part_size: u64,
+    parts: Vec<(u64, String)>,
+}
+
+/// OSS multipart upload (InitiateMultipartUpload / UploadPart / CompleteMultipartUpload),
+/// which the SDK does not provide
+pub struct MultipartUploader<'a> {
+    config: &'a OssConfig,
+    client: Client,
+    part_size: u64,
+    checkpoint_dir: PathBuf,
+}
+
+/// Base64 HMAC-SHA1 signature of an OSS string-to-sign
+fn sign(secret: &str, string_to_sign: &str) -> String {
+    let mut mac: Hmac<Sha1> = Hmac::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(string_to_sign.as_bytes());
+    general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// Text of the first `<tag>` element in an XML response
+fn xml_value<'b>(body: &'b str, tag: &str) -> Option<&'b str> {
+    let start = body.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = body[start..].find(&format!("</{}>", tag))?;
+    Some(&body[start..start + end])
+}
+
+fn oss_error(e: impl std::fmt::Display) -> TransferError {
+    TransferError::OssError(e.to_string())
+}
+
+impl<'a> MultipartUploader<'a> {
+    pub fn new(config: &'a OssConfig, part_size: u64) -> Self {
+        MultipartUploader {
+            config,
+            client: Client::new(),
+            part_size: part_size.max(1),
+            checkpoint_dir: std::env::temp_dir().join("ci-transfer-checkpoints"),
+        }
+    }
+
+    /// Request URL of `key`, percent-encoded there while the string to sign keeps it raw
+    fn url(&self, key: &str, query: &str) -> String {
+        let endpoint = &self.config.oss_endpoint;
+        let (scheme, host) = if let Some(host) = endpoint.strip_prefix("https://") {
+            ("https", host)
+        } else {
+            ("http", endpoint.trim_start_matches("http://"))
+        };
+        format!("{}://{}.{}{}?{}", scheme, self.config.oss_bucket, host, uri_encode(key), query)
+    }
+
+    /// Signed request for `key` with a sub-resource `query`, failing on non-2xx responses
+    fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Response, TransferError> {
+        let date = chrono::Utc::now()
+            .format("%a, %d %b %Y %T GMT")
+            .to_string();
+        let string_to_sign = format!(
+            "{}\n\n{}\n{}\n/{}{}?{}",
+            method, content_type, date, self.config.oss_bucket, key, query
+        );
+        let authorization = format!(
+            "OSS {}:{}",
+            self.config.key_id,
+            sign(&self.config.key_secret, &string_to_sign)
+        );
+        let mut request = self
+            .client
+            .request(method, self.url(key, query))
+            .header("Date", date)
+            .header("Authorization", authorization)
+            .body(body);
+        if !content_type.is_empty() {
+            request = request.header("Content-Type", content_type);
+        }
+        let response = request.send().map_err(oss_error)?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().unwrap_or_default();
+            return Err(TransferError::OssError(format!(
+                "{} {}: {}",
+                status,
+                key,
+                xml_value(&text, "Code").unwrap_or(&text)
+            )));
+        }
+        Ok(response)
+    }
+
+    fn initiate(&self, key: &str) -> Result<String, TransferError> {
+        let text = self
+            .send(Method::POST, key, "uploads", "", Vec::new())?
+            .text()
+            .map_err(oss_error)?;
+        xml_value(&text, "UploadId")
+            .map(str::to_string)
+            .ok_or_else(|| TransferError::OssError(format!("No UploadId for {}", key)))
+    }
+
+    fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u64,
+        body: Vec<u8>,
+    ) -> Result<String, TransferError> {
+        let query = format!("partNumber={}&uploadId={}", number, upload_id);
+        let response = self.send(Method::PUT, key, &query, "", body)?;
+        response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| TransferError::OssError(format!("No ETag for part {} of {}", number, key)))
+    }
+
+    fn complete(&self, key: &str, checkpoint: &Checkpoint) -> Result<(), TransferError> {
+        let mut parts = checkpoint.parts.clone();
+        parts.sort();
+        let body: String = parts
+            .iter()
+            .map(|(number, etag)| {
+                format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number, etag)
+            })
+            .collect();
+        let body = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", body);
+        let query = format!("uploadId={}", checkpoint.upload_id);
+        self.send(Method::POST, key, &query, "application/xml", body.into_bytes())?;
+        Ok(())
+    }
+
+    fn checkpoint_path(&self, key: &str, local_path: &Path) -> PathBuf {
+        let local = fs::canonicalize(local_path).unwrap_or_else(|_| local_path.to_path_buf());
+        let mut hasher = Md5::new();
+        hasher.update(format!("{}{}\n{}", self.config.oss_bucket, key, local.display()));
+        self.checkpoint_dir
+            .join(format!("{:x}.json", hasher.finalize()))
+    }
+
+    fn save(&self, path: &Path, checkpoint: &Checkpoint) -> Result<(), TransferError> {
+        fs::create_dir_all(&self.checkpoint_dir)?;
+        fs::write(path, serde_json::to_vec(checkpoint).map_err(TransferError::JsonParseError)?)?;
+        Ok(())
+    }
+
+    /// Upload `local_path` in parts of `part_size` bytes, resuming from a matching checkpoint
+    pub fn upload(&self, key: &str, local_path: &Path, progress: &Progress) -> Result<(), TransferError> {
+        let metadata = fs::metadata(local_path)?;
+        let size = metadata.len();
+        let mtime = mtime_secs(&metadata);
+        let checkpoint_path = self.checkpoint_path(key, local_path);
+        let resumed = fs::read(&checkpoint_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Checkpoint>(&data).ok())
+            .filter(|c| c.size == size && c.mtime == mtime && c.part_size == self.part_size);
+        let mut checkpoint = match resumed {
+            Some(checkpoint) => {
+                println!(
+                    "oss multipart: resuming {} ({} part(s) already uploaded)",
+                    key,
+                    checkpoint.parts.len()
+                );
+                checkpoint
+            }
+            None => Checkpoint {
+                upload_id: self.initiate(key)?,
+                size,
+                mtime,
+                part_size: self.part_size,
+                parts: Vec::new(),
+            },
+        };
+        self.save(&checkpoint_path, &checkpoint)?;
+
+        let part_count = size.div_ceil(self.part_size).max(1);
+        let mut file = File::open(local_path)?;
+        for number in 1..=part_count {
+            let offset = (number - 1) * self.part_size;
+            let length = self.part_size.min(size - offset);
+            if checkpoint.parts.iter().any(|(done, _)| *done == number) {
+                progress.add_bytes(length);
+                continue;
+            }
+            let mut buffer = vec![0; length as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            let etag = match self.upload_part(key, &checkpoint.upload_id, number, buffer) {
+                Ok(etag) => etag,
+                Err(e) => {
+                    // The upload was aborted or expired, start over on the next run
+                    if e.to_string().contains("NoSuchUpload") {
+                        let _ = fs::remove_file(&checkpoint_path);
+                    }
+                    return Err(e);
+                }
+            };
+            checkpoint.parts.push((number, etag));
+            self.save(&checkpoint_path, &checkpoint)?;
+            progress.add_bytes(length);
+            progress.report(&format!("{} part {}/{}", key, number, part_count));
+        }
+
+        self.complete(key, &checkpoint)?;
+        let _ = fs::remove_file(&checkpoint_path);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sign() {
+    let string_to_sign = "PUT\nODBGOERFMDMzQTczRUY3NUE3NzA5QzdFNUYzMDQxNEM=\ntext/html\nThu, 17 Nov 2005 18:49:58 GMT\nx-oss-magic:abracadabra\nx-oss-meta-author:foo@example.com\n/oss-example/nelson";
+    assert_eq!(
+        sign("OtxrzxIsfpFjA7SwPzILwy8Bw21TLhquhboDYROV", string_to_sign),
+        "fV5fq7DPwNbrrig7nvUSZIVyruI="
+    );
+    let body = "<InitiateMultipartUploadResult><Bucket>b</Bucket><UploadId>0004B9894A22E5B1888A1E29F823</UploadId></InitiateMultipartUploadResult>";
+    assert_eq!(xml_value(body, "UploadId"), Some("0004B9894A22E5B1888A1E29F823"));
+    assert_eq!(xml_value(body, "Code"), None);
+}
+
+#[test]
+fn test_url_encodes_key() {
+    let config = OssConfig {
+        path: "/site/".into(),
+        oss_bucket: "bucket".into(),
+        oss_endpoint: "https://oss-cn-hangzhou.aliyuncs.com".into(),
+        key_id: "id".into(),
+        key_secret: "secret".into(),
+        override_existing: None,
+    };
+    let uploader = MultipartUploader::new(&config, 1);
+    assert_eq!(
+        uploader.url("/site/v1 #2?x=100%+b.zip", "uploads"),
+        "https://bucket.oss-cn-hangzhou.aliyuncs.com/site/v1%20%232%3Fx%3D100%25%2Bb.zip?uploads"
+    );
+}
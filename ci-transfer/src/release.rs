@@ -0,0 +1,217 @@
+use ssh2::Session;
+use std::path::Path;
+
+use crate::error::TransferError;
+use crate::command::shell_quote;
+use crate::ssh::exec_remote;
+use crate::Args;
+
+/// A Capistrano-style release: `<root>/releases/<name>` with `<root>/current` pointing to it
+pub struct Release {
+    root: String,
+    name: String,
+}
+
+impl Release {
+    pub fn new(root: &str, name: &str) -> Self {
+        Release {
+            root: root.trim_end_matches('/').to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn releases_dir(&self) -> String {
+        format!("{}/releases", self.root)
+    }
+
+    pub fn dir(&self) -> String {
+        format!("{}/{}", self.releases_dir(), self.name)
+    }
+
+    /// Remote path handed to `transfer`: a single source directory is uploaded as the
+    /// release itself, anything else is placed inside the release directory
+    pub fn upload_path(&self, sources: &[String]) -> String {
+        if sources.len() == 1 && Path::new(&sources[0]).is_dir() {
+            self.dir()
+        } else {
+            format!("{}/", self.dir())
+        }
+    }
+}
+
+/// Default release name: the current UTC timestamp
+pub fn default_release_name() -> String {
+    chrono::Utc::now().format("%Y%m%d%H%M%S").to_string()
+}
+
+/// Release name from --release-name, defaulting to a UTC timestamp
+pub fn release_name(args: &Args) -> String {
+    args.release_name.clone().unwrap_or_else(default_release_name)
+}
+
+fn run(session: &Session, command: &str) -> Result<String, TransferError> {
+    let (output, status) = exec_remote(session, command)?;
+    if status != 0 {
+        return Err(TransferError::Other(format!(
+            "Release command `{}` failed with exit status {}: {}",
+            command,
+            status,
+            output.trim()
+        )));
+    }
+    Ok(output)
+}
+
+/// Create an empty release directory to upload into
+pub fn prepare_release(session: &Session, release: &Release) -> Result<(), TransferError> {
+    let dir = release.dir();
+    run(
+        session,
+        &format!("test ! -e {0} && mkdir -p {0}", shell_quote(&dir)),
+    )
+    .map_err(|_| TransferError::Other(format!("Release directory {} already exists or cannot be created", dir)))?;
+    println!("Prepared release directory {}", dir);
+    Ok(())
+}
+
+/// Atomically point `current` at `name` by renaming a freshly created symlink over it
+fn switch_current(session: &Session, root: &str, name: &str) -> Result<(), TransferError> {
+    let current = format!("{}/current", root);
+    let staging = format!("{}/current.tmp", root);
+    run(
+        session,
+        &format!(
+            "ln -sfn {} {} && mv -Tf {} {}",
+            shell_quote(&format!("releases/{}", name)),
+            shell_quote(&staging),
+            shell_quote(&staging),
+            shell_quote(&current)
+        ),
+    )?;
+    println!("Switched {} -> releases/{}", current, name);
+    Ok(())
+}
+
+/// Release names ordered from oldest to newest
+fn list_releases(session: &Session, root: &str) -> Result<Vec<String>, TransferError> {
+    let output = run(
+        session,
+        &format!(
+            "cd {} 2>/dev/null && ls -1tr || true",
+            shell_quote(&format!("{}/releases", root))
+        ),
+    )?;
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn current_release(session: &Session, root: &str) -> Result<Option<String>, TransferError> {
+    let (output, status) = exec_remote(
+        session,
+        &format!("readlink {}", shell_quote(&format!("{}/current", root))),
+    )?;
+    if status != 0 {
+        return Ok(None);
+    }
+    Ok(output
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .map(str::to_string))
+}
+
+/// Releases to delete so that only the newest `keep` remain, never touching the current one
+fn releases_to_prune(releases: &[String], current: Option<&str>, keep: usize) -> Vec<String> {
+    let excess = releases.len().saturating_sub(keep);
+    releases
+        .iter()
+        .take(excess)
+        .filter(|name| Some(name.as_str()) != current)
+        .cloned()
+        .collect()
+}
+
+/// Switch `current` to the uploaded release and remove old releases beyond `keep`
+pub fn activate_release(session: &Session, release: &Release, keep: usize) -> Result<(), TransferError> {
+    switch_current(session, &release.root, &release.name)?;
+
+    let releases = list_releases(session, &release.root)?;
+    for name in releases_to_prune(&releases, Some(&release.name), keep) {
+        let dir = format!("{}/{}", release.releases_dir(), name);
+        run(session, &format!("rm -rf {}", shell_quote(&dir)))?;
+        println!("Removed old release {}", dir);
+    }
+    Ok(())
+}
+
+/// Point `current` back to the release before the active one, or to `to` if given
+pub fn rollback(session: &Session, root: &str, to: Option<&str>) -> Result<(), TransferError> {
+    let root = root.trim_end_matches('/');
+    let releases = list_releases(session, root)?;
+    let current = current_release(session, root)?;
+
+    let target = match to {
+        Some(name) => {
+            if !releases.iter().any(|release| release == name) {
+                return Err(TransferError::Other(format!(
+                    "Release {} not found in {}/releases",
+                    name, root
+                )));
+            }
+            name.to_string()
+        }
+        None => {
+            let position = current
+                .as_ref()
+                .and_then(|current| releases.iter().position(|release| release == current))
+                .ok_or_else(|| {
+                    TransferError::Other(format!("No current release found in {}", root))
+                })?;
+            if position == 0 {
+                return Err(TransferError::Other(
+                    "No previous release to roll back to".into(),
+                ));
+            }
+            releases[position - 1].clone()
+        }
+    };
+
+    println!(
+        "Rolling back {} -> {}",
+        current.as_deref().unwrap_or("(none)"),
+        target
+    );
+    switch_current(session, root, &target)
+}
+
+#[test]
+fn test_releases_to_prune() {
+    let releases: Vec<String> = ["r1", "r2", "r3", "r4"].iter().map(|s| s.to_string()).collect();
+    assert_eq!(releases_to_prune(&releases, Some("r4"), 2), vec!["r1", "r2"]);
+    assert_eq!(releases_to_prune(&releases, Some("r1"), 2), vec!["r2"]);
+    assert!(releases_to_prune(&releases, Some("r4"), 5).is_empty());
+}
+
+#[test]
+fn test_release_upload_path() {
+    let release = Release::new("/srv/app/", "20240101000000");
+    assert_eq!(release.dir(), "/srv/app/releases/20240101000000");
+    assert_eq!(
+        release.upload_path(&["src".to_string()]),
+        "/srv/app/releases/20240101000000"
+    );
+    assert_eq!(
+        release.upload_path(&["Cargo.toml".to_string()]),
+        "/srv/app/releases/20240101000000/"
+    );
+}
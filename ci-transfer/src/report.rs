@@ -0,0 +1,286 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use clap::ArgEnum;
+use serde::Serialize;
+
+use crate::error::TransferError;
+use crate::fanout::{HostOutcome, HostResult};
+use crate::mask::mask;
+use crate::Args;
+
+/// Format of the --report written at the end of a run
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+}
+
+#[derive(Serialize, Debug)]
+struct CommandReport {
+    stage: &'static str,
+    command: String,
+    status: i32,
+    duration_ms: u128,
+}
+
+#[derive(Serialize, Debug)]
+struct DestinationReport {
+    kind: &'static str,
+    target: String,
+    /// "ok", "failed" or "skipped"
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    uploaded: usize,
+    skipped: usize,
+    deleted: usize,
+    bytes: u64,
+    duration_ms: u128,
+    commands: Vec<CommandReport>,
+}
+
+/// Results of every destination of one run
+#[derive(Serialize, Debug)]
+pub struct Report {
+    success: bool,
+    duration_ms: u128,
+    uploaded: usize,
+    bytes: u64,
+    destinations: Vec<DestinationReport>,
+    errors: Vec<String>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            success: true,
+            duration_ms: 0,
+            uploaded: 0,
+            bytes: 0,
+            destinations: Vec::new(),
+            errors: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, kind: &'static str, target: String, outcome: &HostOutcome, duration: Duration) {
+        let mut destination = DestinationReport {
+            kind,
+            target,
+            result: "ok",
+            error: None,
+            uploaded: 0,
+            skipped: 0,
+            deleted: 0,
+            bytes: 0,
+            duration_ms: duration.as_millis(),
+            commands: Vec::new(),
+        };
+        let stats = match outcome {
+            HostOutcome::Success(stats) => Some(stats),
+            HostOutcome::Failed(error, stats) => {
+                destination.result = "failed";
+                destination.error = Some(error.clone());
+                Some(stats)
+            }
+            HostOutcome::Skipped => {
+                destination.result = "skipped";
+                None
+            }
+        };
+        if let Some(stats) = stats {
+            destination.uploaded = stats.uploaded;
+            destination.skipped = stats.skipped;
+            destination.deleted = stats.deleted;
+            destination.bytes = stats.bytes;
+            destination.commands = stats
+                .commands
+                .iter()
+                .map(|(stage, result)| CommandReport {
+                    stage,
+                    command: result.command.clone(),
+                    status: result.status,
+                    duration_ms: result.duration.as_millis(),
+                })
+                .collect();
+        }
+        self.uploaded += destination.uploaded;
+        self.bytes += destination.bytes;
+        self.destinations.push(destination);
+    }
+
+    pub fn add_hosts(&mut self, results: &[HostResult]) {
+        for result in results {
+            self.add("ssh", result.host.clone(), &result.outcome, result.duration);
+        }
+    }
+
+    /// Record the errors of the run and stop the clock
+    pub fn finish(&mut self, errors: &[String]) {
+        self.errors = errors.to_vec();
+        self.success = errors.is_empty();
+        self.duration_ms = self.started.elapsed().as_millis();
+    }
+
+    /// Markdown table for the GitHub Actions job summary
+    fn markdown(&self) -> String {
+        let mut markdown = format!(
+            "### ci-transfer {}\n\n| Destination | Result | Duration | Uploaded | Skipped | Deleted | Bytes |\n|---|---|---|---|---|---|---|\n",
+            if self.success { "succeeded" } else { "failed" }
+        );
+        for destination in &self.destinations {
+            markdown += &format!(
+                "| {} `{}` | {} | {:.1}s | {} | {} | {} | {} |\n",
+                destination.kind,
+                destination.target,
+                destination.result,
+                destination.duration_ms as f64 / 1000.0,
+                destination.uploaded,
+                destination.skipped,
+                destination.deleted,
+                destination.bytes
+            );
+        }
+        let commands: Vec<_> = self
+            .destinations
+            .iter()
+            .flat_map(|destination| destination.commands.iter().map(move |command| (destination, command)))
+            .collect();
+        if !commands.is_empty() {
+            markdown += "\n| Destination | Stage | Command | Exit status | Duration |\n|---|---|---|---|---|\n";
+            for (destination, command) in commands {
+                markdown += &format!(
+                    "| `{}` | {} | `{}` | {} | {:.1}s |\n",
+                    destination.target,
+                    command.stage,
+                    command.command.replace('|', "\\|"),
+                    command.status,
+                    command.duration_ms as f64 / 1000.0
+                );
+            }
+        }
+        for error in &self.errors {
+            markdown += &format!("\n> {}\n", error.replace('\n', "\n> "));
+        }
+        markdown
+    }
+
+    /// `key=value` lines for `$GITHUB_OUTPUT`
+    fn outputs(&self) -> String {
+        let skipped: usize = self.destinations.iter().map(|destination| destination.skipped).sum();
+        let deleted: usize = self.destinations.iter().map(|destination| destination.deleted).sum();
+        format!(
+            "success={}\nuploaded={}\nskipped={}\ndeleted={}\nbytes={}\nduration={:.1}\n",
+            self.success,
+            self.uploaded,
+            skipped,
+            deleted,
+            self.bytes,
+            self.duration_ms as f64 / 1000.0
+        )
+    }
+}
+
+/// Whether ci-transfer runs as a GitHub Actions step
+pub fn github_actions() -> bool {
+    env::var("GITHUB_ACTIONS").is_ok_and(|value| value == "true")
+}
+
+/// Escape a workflow command message, see "Workflow commands for GitHub Actions"
+fn escape_annotation(message: &str) -> String {
+    message
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn append(path: String, content: &str) -> Result<(), TransferError> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Write the --report and, under GitHub Actions, the job summary, step outputs and error annotations
+pub fn publish(args: &Args, report: &Report) -> Result<(), TransferError> {
+    if args.report == Some(ReportFormat::Json) {
+        let json = serde_json::to_string_pretty(report).map_err(TransferError::JsonParseError)?;
+        match &args.report_file {
+            Some(path) => fs::write(path, mask(&json))?,
+            None => println!("{}", json),
+        }
+    }
+
+    if !github_actions() {
+        return Ok(());
+    }
+    if let Ok(path) = env::var("GITHUB_STEP_SUMMARY") {
+        append(path, &mask(&report.markdown()))?;
+    }
+    if let Ok(path) = env::var("GITHUB_OUTPUT") {
+        append(path, &report.outputs())?;
+    }
+    for error in &report.errors {
+        println!("::error title=ci-transfer::{}", escape_annotation(error));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_report() {
+    use crate::backend::TransferStats;
+    use crate::command::CommandResult;
+
+    let mut report = Report::new();
+    let stats = TransferStats {
+        uploaded: 3,
+        skipped: 1,
+        bytes: 2048,
+        commands: vec![(
+            "post-transfer",
+            CommandResult {
+                command: "systemctl restart app".into(),
+                status: 0,
+                duration: Duration::from_millis(1500),
+            },
+        )],
+        ..TransferStats::default()
+    };
+    report.add("ssh", "10.0.0.1".into(), &HostOutcome::Success(stats), Duration::from_secs(2));
+    // A failed destination keeps the commands that ran, the failed one with its exit status
+    let stats = TransferStats {
+        commands: vec![(
+            "pre-transfer",
+            CommandResult {
+                command: "systemctl stop app".into(),
+                status: 5,
+                duration: Duration::from_millis(200),
+            },
+        )],
+        ..TransferStats::default()
+    };
+    let error = "Command Failed: `systemctl stop app` exited with status 5";
+    report.add(
+        "ssh",
+        "10.0.0.2".into(),
+        &HostOutcome::Failed(error.into(), stats),
+        Duration::from_secs(1),
+    );
+    report.finish(&[format!("SSH transfer to 10.0.0.2 failed: {}", error)]);
+
+    let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["success"], false);
+    assert_eq!(json["bytes"], 2048);
+    assert_eq!(json["destinations"][0]["commands"][0]["status"], 0);
+    assert_eq!(json["destinations"][1]["result"], "failed");
+    assert_eq!(json["destinations"][1]["commands"][0]["status"], 5);
+
+    let markdown = report.markdown();
+    assert!(markdown.contains("| ssh `10.0.0.1` | ok | 2.0s | 3 | 1 | 0 | 2048 |"));
+    assert!(markdown.contains("| `10.0.0.1` | post-transfer | `systemctl restart app` | 0 | 1.5s |"));
+    assert!(report.outputs().starts_with("success=false\nuploaded=3\nskipped=1\n"));
+    assert_eq!(escape_annotation("50% done\nfailed"), "50%25 done%0Afailed");
+}
@@ -0,0 +1,347 @@
+use std::fs::File;
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use reqwest::blocking::{Body, Client, Response};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::backend::{transfer, Backend, DirLayout, FileJob, Progress, TransferStats};
+use crate::command::CommandResult;
+use crate::error::TransferError;
+use crate::mask::add_secret;
+use crate::object_store::{local_md5, object_key, uri_encode};
+use crate::ssh::local_sha256;
+use crate::Args;
+
+/// S3 compatible destination (AWS S3, MinIO, ...), given as JSON or base64 encoded JSON
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct S3Config {
+    /// `https://s3.amazonaws.com`, `http://127.0.0.1:9000`, scheme defaults to https
+    endpoint: String,
+    #[serde(default = "default_region")]
+    region: String,
+    bucket: String,
+    access_key_id: String,
+    secret_access_key: String,
+    path: String,
+    /// Address objects as `endpoint/bucket/key` instead of `bucket.endpoint/key`, needed by MinIO
+    #[serde(default)]
+    path_style: bool,
+    #[serde(default)]
+    override_existing: Option<bool>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+pub fn parse_destination_s3(destination: &str) -> Result<S3Config, TransferError> {
+    if destination.is_empty() {
+        return Err(TransferError::Other("Destination cannot be empty".into()));
+    }
+    if let Ok(decoded) = general_purpose::STANDARD.decode(destination) {
+        if let Ok(s) = std::str::from_utf8(&decoded) {
+            add_secret(destination);
+            return parse_destination_s3(s);
+        }
+    }
+    let config: S3Config =
+        serde_json::from_str(destination).map_err(TransferError::JsonParseError)?;
+    add_secret(&config.secret_access_key);
+    Ok(config)
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac: Hmac<Sha256> = Hmac::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+impl S3Config {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn target(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.path.trim_start_matches('/'))
+    }
+
+    /// Request URL, Host header and canonical URI of an object key
+    fn locate(&self, key: &str) -> (String, String, String) {
+        let (scheme, host) = match self.endpoint.split_once("://") {
+            Some((scheme, host)) => (scheme, host.trim_end_matches('/')),
+            None => ("https", self.endpoint.trim_end_matches('/')),
+        };
+        let key = uri_encode(key.trim_start_matches('/'));
+        let (host, uri) = if self.path_style {
+            (host.to_string(), format!("/{}/{}", self.bucket, key))
+        } else {
+            (format!("{}.{}", self.bucket, host), format!("/{}", key))
+        };
+        (format!("{}://{}{}", scheme, host, uri), host, uri)
+    }
+
+    /// AWS Signature Version 4 Authorization header for a request without query parameters
+    fn authorization(
+        &self,
+        method: &Method,
+        uri: &str,
+        host: &str,
+        payload_hash: &str,
+        amz_date: &str,
+    ) -> String {
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, uri, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex_sha256(canonical_request.as_bytes())
+        );
+        let key = hmac_sha256(format!("AWS4{}", self.secret_access_key).as_bytes(), date);
+        let key = hmac_sha256(&key, &self.region);
+        let key = hmac_sha256(&key, "s3");
+        let key = hmac_sha256(&key, "aws4_request");
+        let signature: String = hmac_sha256(&key, &string_to_sign)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        )
+    }
+}
+
+/// Uploads into one S3 bucket
+struct S3Client<'a> {
+    config: &'a S3Config,
+    client: Client,
+    args: &'a Args,
+}
+
+impl<'a> S3Client<'a> {
+    fn send(
+        &self,
+        method: Method,
+        key: &str,
+        payload_hash: &str,
+        body: Option<Body>,
+    ) -> Result<Response, TransferError> {
+        let (url, host, uri) = self.config.locate(key);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = self
+            .config
+            .authorization(&method, &uri, &host, payload_hash, &amz_date);
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("Authorization", authorization);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        request
+            .send()
+            .map_err(|e| TransferError::S3Error(e.to_string()))
+    }
+
+    fn check(response: Response, key: &str) -> Result<Response, TransferError> {
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let text = response.text().unwrap_or_default();
+        Err(TransferError::S3Error(format!("{} {}: {}", status, key, text)))
+    }
+
+    fn put_object(&self, key: &str, local_path: &Path) -> Result<(), TransferError> {
+        let payload_hash = local_sha256(local_path)?;
+        let file = File::open(local_path)?;
+        let size = file.metadata()?.len();
+        let response = self.send(
+            Method::PUT,
+            key,
+            &payload_hash,
+            Some(Body::sized(file, size)),
+        )?;
+        Self::check(response, key)?;
+        Ok(())
+    }
+
+    fn put_bytes(&self, key: &str, data: &[u8]) -> Result<(), TransferError> {
+        let response = self.send(Method::PUT, key, &hex_sha256(data), Some(Body::from(data.to_vec())))?;
+        Self::check(response, key)?;
+        Ok(())
+    }
+
+    /// ETag and size of `key`, or None when the object does not exist
+    fn head(&self, key: &str) -> Result<Option<(String, u64)>, TransferError> {
+        let response = self.send(Method::HEAD, key, &hex_sha256(b""), None)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = Self::check(response, key)?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let etag = header("ETag").trim_matches('"').to_lowercase();
+        let size = header("Content-Length").parse().unwrap_or(0);
+        Ok(Some((etag, size)))
+    }
+}
+
+impl Backend for S3Client<'_> {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn concurrency(&self) -> usize {
+        self.args.parallel
+    }
+
+    fn dir_layout(&self) -> DirLayout {
+        DirLayout::Contents
+    }
+
+    fn make_dir(&self, _remote_dir: &str) -> Result<(), TransferError> {
+        // Object storage has no directories, keys carry the full path
+        Ok(())
+    }
+
+    fn put_file(&self, job: &FileJob, progress: &Progress) -> Result<Option<&'static str>, TransferError> {
+        let key = object_key(&job.remote);
+        let keep_existing = self.config.override_existing == Some(false);
+        if keep_existing || self.args.sync {
+            if let Some((etag, size)) = self.head(&key)? {
+                if keep_existing {
+                    return Ok(Some("exists"));
+                }
+                if size == job.size && etag == local_md5(&job.local_path)? {
+                    return Ok(Some("unchanged"));
+                }
+            }
+        }
+        self.put_object(&key, &job.local_path)?;
+        progress.add_bytes(job.size);
+        if self.args.verify {
+            let expected = local_md5(&job.local_path)?;
+            let actual = self.head(&key)?.map(|(etag, _)| etag).unwrap_or_default();
+            if actual != expected {
+                return Err(TransferError::ChecksumMismatch {
+                    path: key,
+                    expected,
+                    actual,
+                });
+            }
+            println!("s3 verify: {} md5 {}", key, expected);
+        }
+        for sidecar in &self.args.sidecar {
+            let (key, digest) = sidecar.object(&key, &job.local_path)?;
+            self.put_bytes(&key, digest.as_bytes())?;
+        }
+        Ok(None)
+    }
+
+    fn run_command(&self, command: &str) -> Result<CommandResult, TransferError> {
+        Err(TransferError::S3Error(format!(
+            "cannot run `{}`, S3 destinations do not support commands",
+            command
+        )))
+    }
+}
+
+pub fn handle_s3(args: &Args, s3_config: S3Config, stats: &mut TransferStats) -> Result<(), TransferError> {
+    let backend = S3Client {
+        config: &s3_config,
+        client: Client::new(),
+        args,
+    };
+    transfer(&backend, args, &s3_config.path, stats)
+}
+
+#[cfg(test)]
+fn minio_config() -> S3Config {
+    S3Config {
+        endpoint: "http://127.0.0.1:9000".into(),
+        region: default_region(),
+        bucket: "test".into(),
+        access_key_id: "minioadmin".into(),
+        secret_access_key: "minioadmin".into(),
+        path: "/ci-transfer".into(),
+        path_style: true,
+        override_existing: None,
+    }
+}
+
+#[test]
+fn test_s3_signature() {
+    let config = minio_config();
+    let (url, host, uri) = config.locate("/ci-transfer/a b+c.txt");
+    assert_eq!(url, "http://127.0.0.1:9000/test/ci-transfer/a%20b%2Bc.txt");
+    assert_eq!(host, "127.0.0.1:9000");
+    let authorization = config.authorization(
+        &Method::PUT,
+        &uri,
+        &host,
+        &hex_sha256(b"hello"),
+        "20240101T000000Z",
+    );
+    assert_eq!(
+        authorization,
+        "AWS4-HMAC-SHA256 Credential=minioadmin/20240101/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature=371ac60e5af3d5bf5788e70e7f2295ca0d4011e37c2a4cf2a031f16fbcdabc79"
+    );
+}
+
+/// Needs a local MinIO (`minio server /tmp/minio`) with a `test` bucket:
+/// `CI_TRANSFER_MINIO=1 cargo test test_handle_s3 -- --ignored`
+#[test]
+#[ignore]
+fn test_handle_s3() {
+    use clap::Parser;
+    assert_eq!(
+        std::env::var("CI_TRANSFER_MINIO").as_deref(),
+        Ok("1"),
+        "set CI_TRANSFER_MINIO=1 with a local MinIO running"
+    );
+    let args = Args::parse_from(["ci-transfer", "-s", "Cargo.toml"]);
+    let mut config = minio_config();
+    config.path = format!("/ci-transfer-{}/", std::process::id());
+
+    let mut stats = TransferStats::default();
+    handle_s3(&args, config.clone(), &mut stats).unwrap();
+    assert_eq!((stats.uploaded, stats.skipped), (1, 0));
+
+    let client = S3Client {
+        config: &config,
+        client: Client::new(),
+        args: &args,
+    };
+    let key = format!("{}Cargo.toml", config.path);
+    let (etag, size) = client.head(&key).unwrap().expect("uploaded object exists");
+    assert_eq!(etag, local_md5(Path::new("Cargo.toml")).unwrap());
+    assert_eq!(size, std::fs::metadata("Cargo.toml").unwrap().len());
+
+    config.override_existing = Some(false);
+    let mut stats = TransferStats::default();
+    handle_s3(&args, config, &mut stats).unwrap();
+    assert_eq!((stats.uploaded, stats.skipped), (0, 1));
+}
@@ -1,35 +1,443 @@
-use ssh2::Session;
-use std::borrow::Cow;
-use std::fs::{read_dir, File};
-use std::io::{Read, Write};
+use sha2::{Digest, Sha256};
+use ssh2::{
+    CheckResult, FileStat, HashType, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp,
+};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::TcpStream;
-use std::path::Path;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use base64::{engine::general_purpose, Engine as _};
 
+use crate::archive::write_archive;
+use crate::backend::{run_commands, run_scripts, transfer, Backend, FileJob, Progress, SourcePlan, TransferStats};
+use crate::command::{script_interpreter, shell_quote, CommandResult, CommandRunner};
 use crate::error::TransferError;
-use crate::Args;
+use crate::mask::add_secret;
+use crate::template::{command_env, TemplateVars};
+use crate::filter::FileFilter;
+use crate::health::{HealthCheck, HealthFrom};
+use crate::jump::{parse_jump_host, tunnel};
+use crate::release::{activate_release, prepare_release, release_name, rollback, Release};
+use crate::{Args, Command};
 
-fn transfer_file(
-    session: &Session,
-    local_path: &Path,
-    remote_path: &str,
-) -> Result<(), TransferError> {
-    let mut local_file = File::open(local_path)?;
-    let file_size = local_file.metadata()?.len();
-    let mut remote_file = session.scp_send(Path::new(remote_path), 0o644, file_size, None)?;
+/// Transfers over one SSH session: SFTP/SCP uploads and commands through the [`CommandRunner`]
+pub struct SshBackend<'a> {
+    session: &'a Session,
+    sftp: Sftp,
+    args: &'a Args,
+    runner: CommandRunner<'a>,
+}
+
+impl<'a> SshBackend<'a> {
+    pub fn new(session: &'a Session, args: &'a Args, runner: CommandRunner<'a>) -> Result<Self, TransferError> {
+        Ok(SshBackend {
+            session,
+            sftp: session.sftp()?,
+            args,
+            runner,
+        })
+    }
+
+    /// Quick check used by --sync: same size and mtime, or same SHA-256 with --checksum
+    fn is_unchanged(&self, local_path: &Path, remote_path: &str) -> Result<bool, TransferError> {
+        let remote_stat = match self.sftp.stat(Path::new(remote_path)) {
+            Ok(stat) => stat,
+            Err(_) => return Ok(false),
+        };
+        let local_meta = local_path.metadata()?;
+        if remote_stat.size != Some(local_meta.len()) {
+            return Ok(false);
+        }
+        if self.args.checksum {
+            let local_sum = local_sha256(local_path)?;
+            return Ok(remote_sha256(self.session, remote_path)?.as_deref() == Some(&local_sum));
+        }
+        Ok(remote_stat.mtime.is_some() && remote_stat.mtime == Some(mtime_secs(&local_meta)))
+    }
+
+    fn upload_scp(&self, local_path: &Path, remote_path: &str) -> Result<(), TransferError> {
+        let mut local_file = File::open(local_path)?;
+        let local_meta = local_file.metadata()?;
+        let file_size = local_meta.len();
+        // Keep the local mtime in sync mode so the next run can skip the file
+        let times = if self.args.sync {
+            let mtime = mtime_secs(&local_meta);
+            Some((mtime, mtime))
+        } else {
+            None
+        };
+        let mut remote_file =
+            self.session
+                .scp_send(Path::new(remote_path), 0o644, file_size, times)?;
+
+        let mut hasher = self.args.verify.then(Sha256::new);
+        copy_with_progress(&mut local_file, &mut remote_file, 0, file_size, hasher.as_mut())?;
+
+        remote_file.send_eof()?;
+        remote_file.wait_eof()?;
+        remote_file.close()?;
+        remote_file.wait_close()?;
+
+        if let Some(hasher) = hasher {
+            self.verify_remote(remote_path, format!("{:x}", hasher.finalize()))?;
+        }
+        Ok(())
+    }
+
+    /// Compare the SHA-256 of the remote file with the digest computed while sending
+    fn verify_remote(&self, remote_path: &str, expected: String) -> Result<(), TransferError> {
+        let actual = match remote_sha256(self.session, remote_path)? {
+            Some(actual) => actual,
+            // No sha256sum on the remote host, read the file back over SFTP instead
+            None => {
+                let mut remote_file = self.sftp.open(Path::new(remote_path))?;
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut remote_file, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+        };
+        if actual != expected {
+            return Err(TransferError::ChecksumMismatch {
+                path: remote_path.to_string(),
+                expected,
+                actual,
+            });
+        }
+        println!("\nVerified: {} sha256 {}", remote_path, actual);
+        Ok(())
+    }
+
+    /// Whether `<remote_path>.part` was started for the same local file: its size and mtime
+    /// are recorded in `<remote_path>.part.meta` when an upload starts from scratch
+    fn part_matches(&self, meta_path: &str, identity: &str) -> bool {
+        let mut recorded = String::new();
+        match self.sftp.open(Path::new(meta_path)) {
+            Ok(mut file) => file.read_to_string(&mut recorded).is_ok() && recorded == identity,
+            Err(_) => false,
+        }
+    }
+
+    /// Upload over SFTP into `<remote_path>.part`, continuing from its current size if a
+    /// previous attempt for the same local file was interrupted, then rename into place once
+    /// the size matches
+    fn upload_resumable(&self, local_path: &Path, remote_path: &str) -> Result<(), TransferError> {
+        let mut local_file = File::open(local_path)?;
+        let local_meta = local_file.metadata()?;
+        let file_size = local_meta.len();
+        let part_path = format!("{}.part", remote_path);
+        let meta_path = format!("{}.part.meta", remote_path);
+        let identity = format!("{} {}\n", file_size, mtime_secs(&local_meta));
+
+        let offset = match self.sftp.stat(Path::new(&part_path)) {
+            Ok(stat) => stat.size.unwrap_or(0),
+            Err(_) => 0,
+        };
+        let (offset, flags) = if offset > 0 && offset <= file_size && self.part_matches(&meta_path, &identity) {
+            println!("Resuming {} at {}/{} bytes", part_path, offset, file_size);
+            (offset, OpenFlags::WRITE | OpenFlags::CREATE)
+        } else {
+            // A partial upload of another version of the file must not be continued
+            let mut meta_file = self.sftp.open_mode(
+                Path::new(&meta_path),
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o644,
+                OpenType::File,
+            )?;
+            meta_file.write_all(identity.as_bytes())?;
+            (0, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)
+        };
+
+        let mut remote_file =
+            self.sftp
+                .open_mode(Path::new(&part_path), flags, 0o644, OpenType::File)?;
+        remote_file.seek(SeekFrom::Start(offset))?;
+        let mut hasher = self.args.verify.then(Sha256::new);
+        if let Some(hasher) = hasher.as_mut() {
+            // The digest must also cover the part uploaded by an earlier attempt
+            std::io::copy(&mut (&mut local_file).take(offset), hasher)?;
+        }
+        local_file.seek(SeekFrom::Start(offset))?;
+        copy_with_progress(
+            &mut local_file,
+            &mut remote_file,
+            offset,
+            file_size,
+            hasher.as_mut(),
+        )?;
+        remote_file.fsync().ok();
+        drop(remote_file);
+
+        let uploaded_size = self.sftp.stat(Path::new(&part_path))?.size;
+        if uploaded_size != Some(file_size) {
+            return Err(TransferError::Other(format!(
+                "Size mismatch after upload of {}: remote has {:?} bytes, expected {}",
+                part_path, uploaded_size, file_size
+            )));
+        }
 
+        // SFTP v3 rename refuses to overwrite, fall back to an atomic mv on the remote host
+        if self
+            .sftp
+            .rename(Path::new(&part_path), Path::new(remote_path), None)
+            .is_err()
+        {
+            let (output, status) = exec_remote(
+                self.session,
+                &format!("mv -f {} {}", shell_quote(&part_path), shell_quote(remote_path)),
+            )?;
+            if status != 0 {
+                return Err(TransferError::Other(format!(
+                    "Failed to move {} into place: {}",
+                    part_path,
+                    output.trim()
+                )));
+            }
+        }
+        let _ = self.sftp.unlink(Path::new(&meta_path));
+
+        if self.args.sync {
+            let mtime = mtime_secs(&local_meta);
+            self.sftp.setstat(
+                Path::new(remote_path),
+                FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: None,
+                    atime: Some(mtime),
+                    mtime: Some(mtime),
+                },
+            )?;
+        }
+
+        if let Some(hasher) = hasher {
+            self.verify_remote(remote_path, format!("{:x}", hasher.finalize()))?;
+        }
+        Ok(())
+    }
+
+    /// Stream `local_dir` as one compressed tarball into `tar -x` on the remote host
+    fn transfer_archive(&self, local_dir: &Path, remote_dir: &str, filter: &FileFilter) -> Result<(), TransferError> {
+        let compression = self.args.compression;
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&compression.extract_command(&shell_quote(remote_dir)))?;
+        let count = write_archive(&mut channel, local_dir, compression, filter)?;
+        channel.send_eof()?;
+
+        let mut output = String::new();
+        channel.read_to_string(&mut output)?;
+        channel.stderr().read_to_string(&mut output)?;
+        channel.wait_close()?;
+        let status = channel.exit_status()?;
+        if status != 0 {
+            return Err(TransferError::Other(format!(
+                "Extracting archive into {} failed with exit status {}: {}",
+                remote_dir,
+                status,
+                output.trim()
+            )));
+        }
+        println!("Transferred archive: {:?} -> {} ({} files)", local_dir, remote_dir, count);
+        Ok(())
+    }
+
+    /// Remove remote entries of `remote_dir` which no longer exist in `local_dir`,
+    /// returning the number of files deleted
+    fn delete_extraneous(
+        &self,
+        local_dir: &Path,
+        remote_dir: &str,
+        root: &Path,
+        filter: &FileFilter,
+    ) -> Result<usize, TransferError> {
+        let mut deleted = 0;
+        for (remote_path, stat) in self.sftp.readdir(Path::new(remote_dir))? {
+            let name = match remote_path.file_name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let local_path = local_dir.join(name);
+            if local_path.exists() {
+                continue;
+            }
+            // Excluded files are never managed by ci-transfer, leave them in place
+            let relative = local_path.strip_prefix(root).unwrap_or(&local_path);
+            if filter.is_excluded(relative, stat.is_dir()) {
+                continue;
+            }
+            deleted += self.remove_remote(&remote_path, stat.is_dir())?;
+        }
+        Ok(deleted)
+    }
+
+    fn remove_remote(&self, remote_path: &Path, is_dir: bool) -> Result<usize, TransferError> {
+        let mut deleted = 0;
+        if is_dir {
+            for (child, stat) in self.sftp.readdir(remote_path)? {
+                deleted += self.remove_remote(&child, stat.is_dir())?;
+            }
+            self.sftp.rmdir(remote_path)?;
+        } else {
+            self.sftp.unlink(remote_path)?;
+            deleted += 1;
+        }
+        println!("Deleted: {}", remote_path.display());
+        Ok(deleted)
+    }
+}
+
+impl Backend for SshBackend<'_> {
+    fn name(&self) -> &'static str {
+        "ssh"
+    }
+
+    fn make_dir(&self, remote_dir: &str) -> Result<(), TransferError> {
+        match self.sftp.stat(Path::new(remote_dir)) {
+            Ok(stat) if stat.is_dir() => Ok(()),
+            _ => Ok(self.sftp.mkdir(Path::new(remote_dir), 0o755)?),
+        }
+    }
+
+    fn put_file(&self, job: &FileJob, progress: &Progress) -> Result<Option<&'static str>, TransferError> {
+        let (local_path, remote_path) = (job.local_path.as_path(), job.remote.as_str());
+        if self.args.sync && self.is_unchanged(local_path, remote_path)? {
+            return Ok(Some("unchanged"));
+        }
+
+        if self.args.resumable {
+            self.upload_resumable(local_path, remote_path)?;
+        } else {
+            self.upload_scp(local_path, remote_path)?;
+        }
+        // End the per-file progress line
+        println!();
+        progress.add_bytes(job.size);
+        Ok(None)
+    }
+
+    fn run_command(&self, command: &str) -> Result<CommandResult, TransferError> {
+        self.runner.execute(command)
+    }
+
+    /// Upload the script to /tmp, run it and remove it again whatever the outcome
+    fn run_script(&self, script: &Path, options: &str, arguments: &str) -> Result<CommandResult, TransferError> {
+        let interpreter = script_interpreter(script, self.args.shell)?;
+        // A fresh private directory, so nothing planted in /tmp can redirect the upload
+        let (output, status) = exec_remote(self.session, "mktemp -d /tmp/ci-transfer.XXXXXXXXXX")?;
+        let remote_dir = output.trim().to_string();
+        if status != 0 || remote_dir.is_empty() {
+            return Err(TransferError::Other(format!(
+                "Could not create a temporary directory for {} on the remote host",
+                script.display()
+            )));
+        }
+        let name = script.file_name().unwrap().to_string_lossy();
+        let remote_path = format!("{}/{}", remote_dir, name);
+        println!("Uploading script {} -> {}", script.display(), remote_path);
+        let upload = || -> Result<(), TransferError> {
+            let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+            let mut remote_file = self
+                .sftp
+                .open_mode(Path::new(&remote_path), flags, 0o700, OpenType::File)?;
+            std::io::copy(&mut File::open(script)?, &mut remote_file)?;
+            Ok(())
+        };
+        // Run through the interpreter, /tmp may be mounted noexec
+        let result = upload().and_then(|()| {
+            self.runner.execute(&format!(
+                "{}{} {} {}",
+                options,
+                interpreter,
+                shell_quote(&remote_path),
+                arguments
+            ))
+        });
+        let cleanup = exec_remote(self.session, &format!("rm -rf {}", shell_quote(&remote_dir)));
+        if !matches!(cleanup, Ok((_, 0))) {
+            println!("Warning: could not remove script directory {}", remote_dir);
+        }
+        result
+    }
+
+    fn put_tree(&self, source: &SourcePlan, filter: &FileFilter) -> Result<bool, TransferError> {
+        if !self.args.archive {
+            return Ok(false);
+        }
+        self.transfer_archive(&source.local, &source.remote, filter)?;
+        Ok(true)
+    }
+
+    fn finish_tree(&self, source: &SourcePlan, filter: &FileFilter) -> Result<usize, TransferError> {
+        if !self.args.delete {
+            return Ok(0);
+        }
+        let mut deleted = 0;
+        for (local_dir, remote_dir) in &source.dirs {
+            deleted += self.delete_extraneous(local_dir, remote_dir, &source.local, filter)?;
+        }
+        Ok(deleted)
+    }
+}
+
+pub fn mtime_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn local_sha256(path: &Path) -> Result<String, TransferError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Run a command on the remote host and return its stdout and exit status
+pub fn exec_remote(session: &Session, command: &str) -> Result<(String, i32), TransferError> {
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    Ok((output, channel.exit_status()?))
+}
+
+/// SHA-256 of a remote file via `sha256sum`, `None` if it cannot be computed
+fn remote_sha256(session: &Session, remote_path: &str) -> Result<Option<String>, TransferError> {
+    let (output, status) = exec_remote(session, &format!("sha256sum -- {}", shell_quote(remote_path)))?;
+    if status != 0 {
+        return Ok(None);
+    }
+    Ok(output.split_whitespace().next().map(str::to_lowercase))
+}
+
+/// Copy `reader` to `writer`, printing progress every second; `offset` bytes were sent before.
+/// The copied bytes are also fed to `hasher` when given.
+fn copy_with_progress(
+    reader: &mut File,
+    writer: &mut impl Write,
+    offset: u64,
+    file_size: u64,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<(), TransferError> {
     let mut buffer = vec![0; 1024 * 1024]; // 1MB buffer
-    let mut total_transferred = 0;
+    let mut total_transferred = offset;
     let start_time = Instant::now();
     let mut last_update = Instant::now();
 
     loop {
-        let bytes_read = local_file.read(&mut buffer)?;
+        let bytes_read = reader.read(&mut buffer)?;
         if bytes_read == 0 {
             break;
         }
-        remote_file.write_all(&buffer[..bytes_read])?;
+        writer.write_all(&buffer[..bytes_read])?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..bytes_read]);
+        }
         total_transferred += bytes_read as u64;
 
         // Update progress every second
@@ -39,13 +447,7 @@ fn transfer_file(
         }
     }
 
-    remote_file.send_eof()?;
-    remote_file.wait_eof()?;
-    remote_file.close()?;
-    remote_file.wait_close()?;
-
     print_progress(total_transferred, file_size, start_time.elapsed());
-    println!("\nTransferred: {:?} -> {}", local_path, remote_path);
     Ok(())
 }
 
@@ -59,147 +461,53 @@ fn print_progress(transferred: u64, total: u64, elapsed: Duration) {
     std::io::stdout().flush().unwrap();
 }
 
-fn transfer_directory(
-    session: &Session,
-    local_dir: &Path,
-    remote_dir: &str,
-) -> Result<(), TransferError> {
-    let sftp = session.sftp()?;
-    sftp.mkdir(Path::new(remote_dir), 0o755)?;
-
-    for entry in read_dir(local_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        let file_name = path.file_name().unwrap().to_str().unwrap();
-        let remote_path = format!("{}/{}", remote_dir, file_name);
-
-        if path.is_dir() {
-            transfer_directory(session, &path, &remote_path)?;
-        } else {
-            transfer_file(session, &path, &remote_path)?;
-        }
-    }
-
-    Ok(())
+pub struct SshConfig {
+    username: String,
+    password: Option<String>,
+    ip: String,
+    remote_path: String,
 }
 
-pub fn transfer(session: &Session, sources: &[String], remote_path: &str) -> Result<(), TransferError> {
-    // Handle empty sources case (side-effect only)
-    if sources.is_empty() {
-        println!("No source files specified for SSH transfer - side-effect only operation");
-        return Ok(());
+impl SshConfig {
+    pub fn host(&self) -> &str {
+        &self.ip
     }
 
-    for source in sources {
-        println!("Transferring: {} -> {}", source, remote_path);
-        let source_path = Path::new(source);
-        
-        if !source_path.exists() {
-            return Err(TransferError::Other(format!(
-                "Source path {} does not exist",
-                source
-            )));
-        }
-        
-        if source_path.is_dir() {
-            let dir_name = source_path.file_name().unwrap().to_str().unwrap();
-            let target_dir = if remote_path.ends_with('/') {
-                format!("{}{}", remote_path, dir_name)
-            } else {
-                // For multiple sources, create subdirectories
-                if sources.len() > 1 {
-                    format!("{}/{}", remote_path.trim_end_matches('/'), dir_name)
-                } else {
-                    remote_path.to_string()
-                }
-            };
-            transfer_directory(session, source_path, &target_dir)?;
-        } else {
-            let remote_file_path = if remote_path.ends_with('/') {
-                format!(
-                    "{}{}",
-                    remote_path,
-                    source_path.file_name().unwrap().to_str().unwrap()
-                )
-            } else {
-                // For multiple files, we need to create unique paths
-                if sources.len() > 1 {
-                    let file_name = source_path.file_name().unwrap().to_str().unwrap();
-                    format!("{}/{}", remote_path.trim_end_matches('/'), file_name)
-                } else {
-                    remote_path.to_string()
-                }
-            };
-            transfer_file(session, source_path, &remote_file_path)?;
-        }
+    pub fn remote_path(&self) -> &str {
+        &self.remote_path
     }
-    
-    Ok(())
-}
-
-pub fn execute_ssh_commands(session: &Session, commands: &[String]) -> Result<(), TransferError> {
-    for command in commands {
-        if command.is_empty() {
-            continue;
-        }
-        if let Ok(decoded) = general_purpose::STANDARD.decode(&command) {
-            if let Ok(decoded_str) = std::str::from_utf8(&decoded) {
-                execute_ssh_commands(session, &[decoded_str.to_string()])?;
-                continue;
-            }
-        }
-        let mut channel = session.channel_session()?;
-        let escaped_command = escape_command(command);
-        let wrapped_command = format!("bash -c {}", escaped_command);
-        channel.exec(&wrapped_command)?;
-        let mut output = String::new();
-        channel.read_to_string(&mut output)?;
-        println!("Command: {}", command);
-        println!("Output: {}", output);
-        channel.wait_close()?;
-        println!("Exit status: {}", channel.exit_status()?);
-        println!("---");
-    }
-    Ok(())
-}
 
-fn escape_command(cmd: &str) -> Cow<str> {
-    if cmd.contains('"') || cmd.contains('\\') {
-        let escaped = cmd.replace('"', "\\\"").replace('\\', "\\\\");
-        Cow::Owned(format!("\"{}\"", escaped))
-    } else {
-        Cow::Borrowed(cmd)
+    /// `user@host:path`, without the password
+    pub fn target(&self) -> String {
+        format!("{}@{}:{}", self.username, self.ip, self.remote_path)
     }
 }
 
-pub struct SshConfig {
-    username: String,
-    password: String,
-    ip: String,
-    remote_path: String,
-}
-
 pub fn parse_destination_ssh(destination: &str) -> Result<SshConfig, TransferError> {
     if destination.is_empty() {
         return Err(TransferError::Other("Destination cannot be empty".into()));
     }
-    match general_purpose::STANDARD.decode(&destination) {
-        Ok(decoded) => match std::str::from_utf8(&decoded) {
-            Ok(s) => return parse_destination_ssh(s),
-            _ => (),
-        },
-        _ => (),
+    if let Ok(decoded) = general_purpose::STANDARD.decode(destination) {
+        if let Ok(s) = std::str::from_utf8(&decoded) {
+            add_secret(destination);
+            return parse_destination_ssh(s);
+        }
     }
     let parts: Vec<&str> = destination.split('@').collect();
     if parts.len() != 2 {
         return Err(TransferError::Other("Invalid destination format".into()));
     }
 
+    // Password is optional: user@ip:/path relies on key or agent authentication
     let credentials: Vec<&str> = parts[0].split(':').collect();
-    if credentials.len() != 2 {
+    if credentials.is_empty() || credentials.len() > 2 || credentials[0].is_empty() {
         return Err(TransferError::Other("Invalid credentials format".into()));
     }
 
+    if let Some(password) = credentials.get(1) {
+        add_secret(password);
+    }
+
     let server_info: Vec<&str> = parts[1].split(':').collect();
     if server_info.len() != 2 {
         return Err(TransferError::Other("Invalid server info format".into()));
@@ -207,36 +515,462 @@ pub fn parse_destination_ssh(destination: &str) -> Result<SshConfig, TransferErr
 
     Ok(SshConfig {
         username: credentials[0].to_string(),
-        password: credentials[1].to_string(),
+        password: credentials.get(1).map(|p| p.to_string()),
         ip: server_info[0].to_string(),
         remote_path: server_info[1].to_string(),
     })
 }
 
-pub fn handle_ssh(args: &Args, ssh_config: SshConfig) -> Result<(), TransferError> {
-    let tcp = TcpStream::connect(format!("{}:{}", ssh_config.ip, args.port))?;
+/// Decode an inline private key, which may be given as PEM text or base64 encoded PEM
+pub fn decode_inline_key(key: &str) -> Result<String, TransferError> {
+    if key.contains("-----BEGIN") {
+        return Ok(key.to_string());
+    }
+    let compact: String = key.split_whitespace().collect();
+    general_purpose::STANDARD
+        .decode(compact)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .filter(|decoded| decoded.contains("-----BEGIN"))
+        .ok_or_else(|| {
+            TransferError::Other("Inline SSH key is neither PEM nor base64 encoded PEM".into())
+        })
+}
+
+#[cfg(unix)]
+fn userauth_inline_key(
+    session: &Session,
+    username: &str,
+    key: &str,
+    passphrase: Option<&str>,
+) -> Result<(), TransferError> {
+    session.userauth_pubkey_memory(username, None, key, passphrase)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn userauth_inline_key(
+    session: &Session,
+    username: &str,
+    key: &str,
+    passphrase: Option<&str>,
+) -> Result<(), TransferError> {
+    // libssh2 on Windows is built without in-memory key support, go through a temporary file
+    let key_path = std::env::temp_dir().join(format!("ci-transfer-key-{}", std::process::id()));
+    std::fs::write(&key_path, key)?;
+    let result = session.userauth_pubkey_file(username, None, &key_path, passphrase);
+    let _ = std::fs::remove_file(&key_path);
+    result?;
+    Ok(())
+}
+
+/// OpenSSH style SHA256 fingerprint of the server host key, e.g. `SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8`
+fn host_key_fingerprint(session: &Session) -> Result<String, TransferError> {
+    let hash = session
+        .host_key_hash(HashType::Sha256)
+        .ok_or_else(|| TransferError::Other("Server did not present a host key".into()))?;
+    Ok(format!(
+        "SHA256:{}",
+        general_purpose::STANDARD_NO_PAD.encode(hash)
+    ))
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    let fingerprint = fingerprint.trim();
+    let fingerprint = fingerprint.strip_prefix("SHA256:").unwrap_or(fingerprint);
+    format!("SHA256:{}", fingerprint.trim_end_matches('='))
+}
+
+fn default_known_hosts() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+/// Serializes the read, add and write of the known_hosts file across fan-out threads
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Verify the server host key against a pinned fingerprint or a known_hosts file
+fn verify_host_key(
+    session: &Session,
+    args: &Args,
+    host: &str,
+    port: u16,
+    pinned: Option<&str>,
+) -> Result<(), TransferError> {
+    let fingerprint = host_key_fingerprint(session)?;
+
+    if let Some(pinned) = pinned {
+        let expected = normalize_fingerprint(pinned);
+        if expected != fingerprint {
+            return Err(TransferError::HostKeyMismatch {
+                host: host.to_string(),
+                expected,
+                actual: fingerprint,
+            });
+        }
+        println!("Host key verified against pinned fingerprint {}", fingerprint);
+        return Ok(());
+    }
+
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| TransferError::Other("Server did not present a host key".into()))?;
+    let known_hosts_path = args
+        .known_hosts
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(default_known_hosts);
+    // Held until the new key is written, hosts deployed in parallel would otherwise each write
+    // back the file they read and drop the keys added meanwhile
+    let _known_hosts_lock = KNOWN_HOSTS_LOCK.lock().unwrap();
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(path) = known_hosts_path.as_ref().filter(|path| path.exists()) {
+        known_hosts.read_file(path, KnownHostFileKind::OpenSSH)?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => {
+            println!("Host key verified against known_hosts");
+            Ok(())
+        }
+        CheckResult::Mismatch => Err(TransferError::HostKeyMismatch {
+            host: host.to_string(),
+            expected: "the key recorded in known_hosts".into(),
+            actual: fingerprint,
+        }),
+        CheckResult::NotFound | CheckResult::Failure => {
+            if !args.trust_on_first_use {
+                return Err(TransferError::HostKeyUnknown {
+                    host: host.to_string(),
+                    fingerprint,
+                });
+            }
+            println!("Warning: trusting unknown host key {} for {}", fingerprint, host);
+            if let Some(path) = &args.known_hosts {
+                let entry = if port == 22 {
+                    host.to_string()
+                } else {
+                    format!("[{}]:{}", host, port)
+                };
+                known_hosts.add(&entry, key, "added by ci-transfer", key_type.into())?;
+                known_hosts.write_file(Path::new(path), KnownHostFileKind::OpenSSH)?;
+                println!("Recorded host key in {}", path);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Try every configured authentication method in order: the key file of a jump host, ssh-agent,
+/// key file, inline key, password
+fn authenticate(
+    session: &Session,
+    args: &Args,
+    username: &str,
+    password: Option<&str>,
+    own_key_file: Option<&str>,
+) -> Result<(), TransferError> {
+    let passphrase = args.key_passphrase.as_deref();
+    let mut failures: Vec<String> = Vec::new();
+
+    if let Some(key_file) = own_key_file {
+        match session.userauth_pubkey_file(username, None, Path::new(key_file), passphrase) {
+            Ok(()) => {
+                println!("Authenticated with private key file {}", key_file);
+                return Ok(());
+            }
+            Err(e) => failures.push(format!("key file {}: {}", key_file, e)),
+        }
+    }
+
+    if args.ssh_agent {
+        match session.userauth_agent(username) {
+            Ok(()) => {
+                println!("Authenticated with ssh-agent");
+                return Ok(());
+            }
+            Err(e) => failures.push(format!("ssh-agent: {}", e)),
+        }
+    }
+
+    if let Some(key_file) = &args.key_file {
+        match session.userauth_pubkey_file(username, None, Path::new(key_file), passphrase) {
+            Ok(()) => {
+                println!("Authenticated with private key file {}", key_file);
+                return Ok(());
+            }
+            Err(e) => failures.push(format!("key file {}: {}", key_file, e)),
+        }
+    }
+
+    if let Some(key) = &args.key {
+        match decode_inline_key(key)
+            .and_then(|key| userauth_inline_key(session, username, &key, passphrase))
+        {
+            Ok(()) => {
+                println!("Authenticated with inline private key");
+                return Ok(());
+            }
+            Err(e) => failures.push(format!("inline key: {}", e)),
+        }
+    }
+
+    if let Some(password) = password {
+        match session.userauth_password(username, password) {
+            Ok(()) => return Ok(()),
+            Err(e) => failures.push(format!("password: {}", e)),
+        }
+    }
+
+    if failures.is_empty() {
+        return Err(TransferError::Other(
+            "No SSH authentication method available, provide a password in the destination, --key-file, --key or --ssh-agent".into(),
+        ));
+    }
+    Err(TransferError::Other(format!(
+        "SSH authentication failed ({})",
+        failures.join("; ")
+    )))
+}
+
+/// Socket to `host:port`, directly or tunnelled through every --jump-host in turn.
+/// Each hop verifies its host key against known_hosts and authenticates with its own user,
+/// password and key file, or the key and agent options shared with the destination.
+fn open_stream(args: &Args, host: &str, port: u16) -> Result<TcpStream, TransferError> {
+    let mut via: Option<Session> = None;
+    for jump_host in &args.jump_host {
+        let jump_host = parse_jump_host(jump_host)?;
+        let tcp = match via.take() {
+            Some(session) => tunnel(session, &jump_host.host, jump_host.port)?,
+            None => TcpStream::connect(format!("{}:{}", jump_host.host, jump_host.port))?,
+        };
+        println!("Connected to jump host {}:{}", jump_host.host, jump_host.port);
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        verify_host_key(&session, args, &jump_host.host, jump_host.port, None)?;
+        authenticate(
+            &session,
+            args,
+            &jump_host.username,
+            jump_host.password.as_deref(),
+            jump_host.key_file.as_deref(),
+        )?;
+        via = Some(session);
+    }
+    match via {
+        Some(session) => tunnel(session, host, port),
+        None => Ok(TcpStream::connect(format!("{}:{}", host, port))?),
+    }
+}
+
+fn connect_once(args: &Args, ssh_config: &SshConfig) -> Result<Session, TransferError> {
+    let tcp = open_stream(args, &ssh_config.ip, args.port)?;
     println!("Connected to {}:{}", ssh_config.ip, args.port);
     let mut session = Session::new()?;
     session.set_timeout(0);
     session.set_tcp_stream(tcp);
     session.handshake()?;
-    session.userauth_password(&ssh_config.username, &ssh_config.password)?;
+    verify_host_key(
+        &session,
+        args,
+        &ssh_config.ip,
+        args.port,
+        args.host_key_fingerprint.as_deref(),
+    )?;
+    authenticate(&session, args, &ssh_config.username, ssh_config.password.as_deref(), None)?;
+    Ok(session)
+}
+
+/// Connection drops and SSH protocol errors are worth a retry, everything else is final
+fn is_retryable(error: &TransferError) -> bool {
+    matches!(error, TransferError::IoError(_) | TransferError::SshError(_))
+}
 
-    // Execute precommands if they exist
-    if !args.precommands.is_empty() {
-        println!("Executing pre-transfer commands:");
-        execute_ssh_commands(&session, &args.precommands)?;
-        println!("Pre-transfer commands completed.");
+/// Run `operation` up to `--retries` more times with exponential backoff on retryable errors
+fn with_retry<T>(
+    args: &Args,
+    what: &str,
+    operation: impl FnMut() -> Result<T, TransferError>,
+) -> Result<T, TransferError> {
+    retry(args, what, operation, std::thread::sleep)
+}
+
+/// [`with_retry`], waiting out each backoff with `sleep`
+fn retry<T>(
+    args: &Args,
+    what: &str,
+    mut operation: impl FnMut() -> Result<T, TransferError>,
+    mut sleep: impl FnMut(Duration),
+) -> Result<T, TransferError> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < args.retries && is_retryable(&e) => {
+                let delay = args.retry_delay.saturating_mul(1 << attempt.min(16));
+                attempt += 1;
+                println!(
+                    "\n{} failed: {}. Retrying in {}s ({}/{})",
+                    what, e, delay, attempt, args.retries
+                );
+                sleep(Duration::from_secs(delay));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn connect(args: &Args, ssh_config: &SshConfig) -> Result<Session, TransferError> {
+    with_retry(args, "SSH connection", || connect_once(args, ssh_config))
+}
+
+/// Deploy to one SSH destination, adding what was done to `stats` even when it fails
+pub fn handle_ssh(args: &Args, ssh_config: SshConfig, stats: &mut TransferStats) -> Result<(), TransferError> {
+    let deadline = args
+        .timeout
+        .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+    let env = command_env(args)?;
+    let release = args
+        .release
+        .then(|| Release::new(&ssh_config.remote_path, &release_name(args)));
+    let remote_path = match &release {
+        Some(release) => release.upload_path(&args.source),
+        None => ssh_config.remote_path.clone(),
+    };
+    let vars = TemplateVars::new(args, &ssh_config.ip, &remote_path, release.as_ref());
+    // Validated before connecting, a bad --health-body must not surface after the deploy
+    let health_check = HealthCheck::from_args(args, &vars)?;
+
+    let mut session = connect(args, &ssh_config)?;
+    let runner = CommandRunner::new(&session, &ssh_config.ip, args.command_timeout, deadline, &env, args.shell);
+    let backend = SshBackend::new(&session, args, runner)?;
+
+    if let Some(Command::Rollback { to }) = &args.command {
+        rollback(&session, &ssh_config.remote_path, to.as_deref())?;
+        return run_commands(&backend, "post-rollback", &args.commands, &vars, stats);
     }
 
-    transfer(&session, &args.source, &ssh_config.remote_path)?;
+    run_commands(&backend, "pre-transfer", &args.precommands, &vars, stats)?;
+    run_scripts(&backend, "pre-transfer", &args.prescript, &vars, stats)?;
+    drop(backend);
+
+    if let Some(release) = &release {
+        prepare_release(&session, release)?;
+    }
+
+    // Reconnect and start over on dropped connections, resumable uploads continue where they stopped.
+    // Only the counts of the last attempt are kept, earlier ones would count files twice.
+    let mut transferred = TransferStats::default();
+    let result = with_retry(args, "Transfer", || {
+        transferred = TransferStats::default();
+        let runner = CommandRunner::new(&session, &ssh_config.ip, args.command_timeout, deadline, &env, args.shell);
+        let result = SshBackend::new(&session, args, runner)
+            .and_then(|backend| transfer(&backend, args, &remote_path, &mut transferred));
+        match result {
+            Err(e) if is_retryable(&e) => {
+                session = connect_once(args, &ssh_config)?;
+                Err(e)
+            }
+            result => result,
+        }
+    });
+    stats.uploaded += transferred.uploaded;
+    stats.skipped += transferred.skipped;
+    stats.deleted += transferred.deleted;
+    stats.bytes += transferred.bytes;
+    result?;
+    let runner = CommandRunner::new(&session, &ssh_config.ip, args.command_timeout, deadline, &env, args.shell);
+    let backend = SshBackend::new(&session, args, runner)?;
     println!("\nFile(s) transferred successfully");
+    run_commands(&backend, "post-transfer", &args.commands, &vars, stats)?;
+    run_scripts(&backend, "post-transfer", &args.script, &vars, stats)?;
 
-    if !args.commands.is_empty() {
-        println!("Executing post-transfer commands:");
-        execute_ssh_commands(&session, &args.commands)?;
-        println!("Post-transfer commands completed.");
+    if let Some(release) = &release {
+        activate_release(&session, release, args.keep_releases)?;
+    }
+
+    if let Some(check) = &health_check {
+        let healthy = match args.health_from {
+            HealthFrom::Runner => check.wait_from_runner(),
+            HealthFrom::Host => check.wait_from_host(&session),
+        };
+        if let Err(e) = healthy {
+            println!("\n{}, rolling back", e);
+            // Still run the rollback commands when there is no previous release to switch to
+            if release.is_some() {
+                if let Err(rollback_error) = rollback(&session, &ssh_config.remote_path, None) {
+                    println!("Release rollback failed: {}", rollback_error);
+                }
+            }
+            run_commands(&backend, "rollback", &args.rollback_commands, &vars, stats)?;
+            return Err(e);
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_destination_ssh() {
+    let config = parse_destination_ssh("root:secret@10.0.0.1:/srv/app/").unwrap();
+    assert_eq!(config.username, "root");
+    assert_eq!(config.password.as_deref(), Some("secret"));
+    assert_eq!(config.ip, "10.0.0.1");
+    assert_eq!(config.remote_path, "/srv/app/");
+
+    let config = parse_destination_ssh("deploy@10.0.0.1:/srv/app").unwrap();
+    assert_eq!(config.username, "deploy");
+    assert!(config.password.is_none());
+
+    assert!(parse_destination_ssh("10.0.0.1:/srv/app").is_err());
+    assert!(parse_destination_ssh(":pass@10.0.0.1:/srv/app").is_err());
+}
+
+#[test]
+fn test_normalize_fingerprint() {
+    let expected = "SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8";
+    assert_eq!(normalize_fingerprint(expected), expected);
+    assert_eq!(
+        normalize_fingerprint(" nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8= "),
+        expected
+    );
+}
+
+#[test]
+fn test_with_retry() {
+    use clap::Parser;
+    use ssh2::ErrorCode;
+    let args = Args::parse_from(["ci-transfer", "--retries", "3", "--retry-delay", "2"]);
+    let retried = |error: fn() -> TransferError, failures: usize| {
+        let (mut calls, mut delays) = (0, Vec::new());
+        let result = retry(
+            &args,
+            "Test",
+            || {
+                calls += 1;
+                if calls > failures {
+                    Ok(())
+                } else {
+                    Err(error())
+                }
+            },
+            |delay| delays.push(delay.as_secs()),
+        );
+        (result.is_ok(), calls, delays)
+    };
+    let io = || TransferError::IoError(std::io::ErrorKind::ConnectionReset.into());
+    let ssh = || TransferError::SshError(ssh2::Error::new(ErrorCode::Session(-7), "Unable to send data"));
+    let mismatch = || TransferError::HostKeyMismatch {
+        host: "10.0.0.1".into(),
+        expected: "SHA256:a".into(),
+        actual: "SHA256:b".into(),
+    };
+
+    // Backoff doubles from --retry-delay, and gives up after --retries
+    assert_eq!(retried(io, 2), (true, 3, vec![2, 4]));
+    assert_eq!(retried(ssh, 10), (false, 4, vec![2, 4, 8]));
+    // Everything but connection and SSH errors fails at once
+    assert_eq!(retried(mismatch, 1), (false, 1, vec![]));
+    assert_eq!(retried(|| TransferError::Other("bad config".into()), 1), (false, 1, vec![]));
+}
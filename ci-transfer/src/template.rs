@@ -0,0 +1,215 @@
+use std::env;
+use std::process::Command;
+
+use crate::backend::{plan, DirLayout};
+use crate::command::shell_quote;
+use crate::error::TransferError;
+use crate::filter::FileFilter;
+use crate::release::Release;
+use crate::Args;
+
+/// Values of the `{{...}}` placeholders in the commands run on one destination
+pub struct TemplateVars<'a> {
+    args: &'a Args,
+    host: &'a str,
+    /// Path the sources are transferred to, the release directory in --release mode
+    remote_root: &'a str,
+    release: Option<&'a Release>,
+}
+
+impl<'a> TemplateVars<'a> {
+    pub fn new(args: &'a Args, host: &'a str, remote_root: &'a str, release: Option<&'a Release>) -> Self {
+        TemplateVars {
+            args,
+            host,
+            remote_root,
+            release,
+        }
+    }
+
+    /// Value of a known variable, None for any other name
+    fn value(&self, name: &str) -> Result<Option<String>, TransferError> {
+        if let Some(variable) = name.strip_prefix("env.") {
+            return env::var(variable).map(Some).map_err(|_| {
+                TransferError::Other(format!("Environment variable {} used in a command is not set", variable))
+            });
+        }
+        let value = match name {
+            "host" => self.host.to_string(),
+            "release_dir" => match self.release {
+                Some(release) => release.dir(),
+                None => self.remote_root.trim_end_matches('/').to_string(),
+            },
+            "release" => self.release.map(|release| release.name().to_string()).unwrap_or_default(),
+            "sha" => commit_sha()?,
+            "files" => self.files()?,
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    /// Remote paths of all source files, quoted for the shell and separated by spaces
+    fn files(&self) -> Result<String, TransferError> {
+        if self.args.source.is_empty() {
+            return Ok(String::new());
+        }
+        let filter = FileFilter::from_args(self.args)?;
+        let files: Vec<String> = plan(self.args, self.remote_root, &filter, DirLayout::Named)?
+            .iter()
+            .flat_map(|source| &source.files)
+            .map(|job| shell_quote(&job.remote))
+            .collect();
+        Ok(files.join(" "))
+    }
+
+    /// Replace every `{{name}}` of a known variable in `command`. Other placeholders, such as
+    /// `{{.State.Status}}` or `{{end}}` of docker inspect templates, are left as they are.
+    pub fn render(&self, command: &str) -> Result<String, TransferError> {
+        let mut rendered = String::new();
+        let mut rest = command;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            let name = rest[start + 2..start + end].trim();
+            rendered.push_str(&rest[..start]);
+            match self.value(name)? {
+                Some(value) => rendered.push_str(&value),
+                None => rendered.push_str(&rest[start..start + end + 2]),
+            }
+            rest = &rest[start + end + 2..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
+
+/// Commit being deployed: $GITHUB_SHA, $CI_COMMIT_SHA, or HEAD of the working directory
+fn commit_sha() -> Result<String, TransferError> {
+    for variable in ["GITHUB_SHA", "CI_COMMIT_SHA"] {
+        if let Ok(sha) = env::var(variable) {
+            return Ok(sha);
+        }
+    }
+    let output = Command::new("git").args(["rev-parse", "HEAD"]).output()?;
+    if !output.status.success() {
+        return Err(TransferError::Other(
+            "{{sha}} needs GITHUB_SHA, CI_COMMIT_SHA or a git repository".into(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Whether `name` matches a --forward-env pattern, where `*` matches any run of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=name.len()).any(|index| name.is_char_boundary(index) && matches_pattern(rest, &name[index..]))
+        }
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first == '_' || first.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Variables exported to every command: `--env KEY=VAL` and the local variables matching `--forward-env`
+pub fn command_env(args: &Args) -> Result<Vec<(String, String)>, TransferError> {
+    let mut variables: Vec<(String, String)> = Vec::new();
+    for pattern in &args.forward_env {
+        let mut forwarded: Vec<(String, String)> = env::vars()
+            .filter(|(name, _)| matches_pattern(pattern, name))
+            .collect();
+        forwarded.sort();
+        variables.extend(forwarded);
+    }
+    for variable in &args.env {
+        let Some((name, value)) = variable.split_once('=') else {
+            return Err(TransferError::Other(format!("Invalid --env {}, expected KEY=VAL", variable)));
+        };
+        variables.push((name.to_string(), value.to_string()));
+    }
+    if let Some((name, _)) = variables.iter().find(|(name, _)| !is_variable_name(name)) {
+        return Err(TransferError::Other(format!("Invalid environment variable name {}", name)));
+    }
+    // --env wins over forwarded variables of the same name
+    let mut unique: Vec<(String, String)> = Vec::new();
+    for (name, value) in variables.into_iter().rev() {
+        if !unique.iter().any(|(known, _)| *known == name) {
+            unique.push((name, value));
+        }
+    }
+    unique.reverse();
+    Ok(unique)
+}
+
+/// `KEY='value' ` assignments to put in front of a remote command
+pub fn env_prefix(variables: &[(String, String)]) -> String {
+    variables
+        .iter()
+        .map(|(name, value)| format!("{}={} ", name, shell_quote(value)))
+        .collect()
+}
+
+#[test]
+fn test_render() {
+    use clap::Parser;
+    env::set_var("CI_TRANSFER_TEST_VERSION", "1.2.3");
+    env::set_var("GITHUB_SHA", "0123abc");
+    let args = Args::parse_from(["ci-transfer", "-s", "Cargo.toml", "-s", "src/oss"]);
+    let release = Release::new("/srv/app", "20240101");
+    let vars = TemplateVars::new(&args, "10.0.0.1", "/srv/app/releases/20240101/", Some(&release));
+    assert_eq!(
+        vars.render("cd {{release_dir}} && ./run {{ env.CI_TRANSFER_TEST_VERSION }} {{sha}} {{release}}")
+            .unwrap(),
+        "cd /srv/app/releases/20240101 && ./run 1.2.3 0123abc 20240101"
+    );
+    assert_eq!(
+        vars.render("chmod +x {{files}}").unwrap(),
+        "chmod +x '/srv/app/releases/20240101/Cargo.toml' '/srv/app/releases/20240101/oss/multipart.rs'"
+    );
+    assert_eq!(vars.render("awk '{print $1}' {{").unwrap(), "awk '{print $1}' {{");
+    assert_eq!(
+        vars.render("docker inspect -f '{{.State.Status}}' app").unwrap(),
+        "docker inspect -f '{{.State.Status}}' app"
+    );
+    let go_template = "docker inspect -f '{{range .Mounts}}{{.Source}}{{end}}{{if .State.Running}}up{{else}}down{{end}}' app";
+    assert_eq!(vars.render(go_template).unwrap(), go_template);
+    assert_eq!(vars.render("{{unknown}}").unwrap(), "{{unknown}}");
+    assert!(vars.render("{{env.CI_TRANSFER_TEST_UNSET}}").is_err());
+}
+
+#[test]
+fn test_command_env() {
+    use clap::Parser;
+    env::set_var("CI_TRANSFER_FWD_ONE", "it's");
+    env::set_var("CI_TRANSFER_FWD_TWO", "2");
+    let args = Args::parse_from([
+        "ci-transfer",
+        "--forward-env",
+        "CI_TRANSFER_FWD_*",
+        "--env",
+        "CI_TRANSFER_FWD_TWO=override",
+        "--env",
+        "MODE=a=b",
+    ]);
+    let variables = command_env(&args).unwrap();
+    assert_eq!(
+        env_prefix(&variables),
+        "CI_TRANSFER_FWD_ONE='it'\\''s' CI_TRANSFER_FWD_TWO='override' MODE='a=b' "
+    );
+    assert!(matches_pattern("APP_*_URL", "APP_API_URL"));
+    assert!(!matches_pattern("APP_*", "MY_APP_X"));
+    assert!(command_env(&Args::parse_from(["ci-transfer", "--env", "BAD-NAME=1"])).is_err());
+}